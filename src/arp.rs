@@ -0,0 +1,373 @@
+//! ARP (RFC 826) for IPv4 over Ethernet: finding the hardware addresses of peers on a TAP
+//! device, and telling them ours.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use etherparse::ether_type;
+
+/// An Ethernet hardware address.
+pub(crate) type Mac = [u8; 6];
+
+pub(crate) const BROADCAST: Mac = [0xff; 6];
+
+const HTYPE_ETHERNET: u16 = 1;
+const REQUEST: u16 = 1;
+const REPLY: u16 = 2;
+
+/// Length of an ARP packet for IPv4 over Ethernet.
+const PACKET_LEN: usize = 28;
+
+/// RFC 1122 S2.3.2.1: resolved entries time out, so that a peer whose hardware address
+/// changed is found again.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// RFC 1122 S2.3.2.1: no more than one request a second for the same address.
+const RETRANSMIT: Duration = Duration::from_secs(1);
+
+/// Requests sent for an address before giving up on it, and on the packets held for it.
+const MAX_REQUESTS: u32 = 3;
+
+/// RFC 1122 S2.3.2.2: packets held for an address while it is being resolved, of which the
+/// latest are kept.
+const MAX_HELD: usize = 16;
+
+/// An ARP request or reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Packet {
+    pub(crate) request: bool,
+    /// sender hardware and protocol address
+    pub(crate) sha: Mac,
+    pub(crate) spa: Ipv4Addr,
+    /// target hardware and protocol address
+    pub(crate) tha: Mac,
+    pub(crate) tpa: Ipv4Addr,
+}
+
+impl Packet {
+    /// A request from `sha` and `spa` for the hardware address of `tpa`.
+    pub(crate) fn request(sha: Mac, spa: Ipv4Addr, tpa: Ipv4Addr) -> Self {
+        Packet {
+            request: true,
+            sha,
+            spa,
+            tha: [0; 6],
+            tpa,
+        }
+    }
+
+    /// The reply to this request, telling that `mac` has the address that was asked for.
+    pub(crate) fn reply(&self, mac: Mac) -> Self {
+        Packet {
+            request: false,
+            sha: mac,
+            spa: self.tpa,
+            tha: self.sha,
+            tpa: self.spa,
+        }
+    }
+
+    /// Parse an ARP packet for IPv4 over Ethernet, ignoring any other.
+    pub(crate) fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < PACKET_LEN {
+            return None;
+        }
+        let u16_at = |at: usize| u16::from_be_bytes([packet[at], packet[at + 1]]);
+        let mac_at = |at: usize| -> Mac { packet[at..at + 6].try_into().unwrap() };
+        let ip_at =
+            |at: usize| Ipv4Addr::new(packet[at], packet[at + 1], packet[at + 2], packet[at + 3]);
+        if u16_at(0) != HTYPE_ETHERNET
+            || u16_at(2) != ether_type::IPV4
+            || packet[4] != 6
+            || packet[5] != 4
+        {
+            return None;
+        }
+        let request = match u16_at(6) {
+            REQUEST => true,
+            REPLY => false,
+            _ => return None,
+        };
+        Some(Packet {
+            request,
+            sha: mac_at(8),
+            spa: ip_at(14),
+            tha: mac_at(18),
+            tpa: ip_at(24),
+        })
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; PACKET_LEN] {
+        let mut packet = [0; PACKET_LEN];
+        packet[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        packet[2..4].copy_from_slice(&ether_type::IPV4.to_be_bytes());
+        packet[4] = 6;
+        packet[5] = 4;
+        let op = if self.request { REQUEST } else { REPLY };
+        packet[6..8].copy_from_slice(&op.to_be_bytes());
+        packet[8..14].copy_from_slice(&self.sha);
+        packet[14..18].copy_from_slice(&self.spa.octets());
+        packet[18..24].copy_from_slice(&self.tha);
+        packet[24..28].copy_from_slice(&self.tpa.octets());
+        packet
+    }
+}
+
+#[derive(Debug)]
+enum Entry {
+    Resolved {
+        mac: Mac,
+        expires: Instant,
+    },
+    /// waiting for a reply to the requests sent from `src`
+    Resolving {
+        src: Ipv4Addr,
+        held: VecDeque<Vec<u8>>,
+        requests: u32,
+        next: Instant,
+    },
+}
+
+/// The ARP cache of one interface.
+#[derive(Debug, Default)]
+pub(crate) struct Cache {
+    entries: HashMap<Ipv4Addr, Entry>,
+}
+
+impl Cache {
+    /// The hardware address of `ip`, if it is known at `now`.
+    pub(crate) fn lookup(&mut self, ip: Ipv4Addr, now: Instant) -> Option<Mac> {
+        match self.entries.get(&ip)? {
+            &Entry::Resolved { mac, expires } if expires > now => Some(mac),
+            Entry::Resolved { .. } => {
+                self.entries.remove(&ip);
+                None
+            }
+            Entry::Resolving { .. } => None,
+        }
+    }
+
+    /// Hold `packet`, sent from `src`, until `ip` is resolved.
+    ///
+    /// Returns whether a request for `ip` has to be sent, because none is outstanding yet.
+    pub(crate) fn hold(
+        &mut self,
+        ip: Ipv4Addr,
+        src: Ipv4Addr,
+        packet: Vec<u8>,
+        now: Instant,
+    ) -> bool {
+        let entry = self.entries.entry(ip).or_insert_with(|| Entry::Resolving {
+            src,
+            held: VecDeque::new(),
+            requests: 0,
+            next: now,
+        });
+        let Entry::Resolving {
+            held,
+            requests,
+            next,
+            ..
+        } = entry
+        else {
+            unreachable!("lookup removes expired entries, and resolved ones are not held for");
+        };
+        if held.len() == MAX_HELD {
+            held.pop_front();
+        }
+        held.push_back(packet);
+        if *requests > 0 {
+            return false;
+        }
+        *requests = 1;
+        *next = now + RETRANSMIT;
+        true
+    }
+
+    /// RFC 826: `ip` is at `mac`, as told by a packet from it.
+    ///
+    /// An entry is only added if `insert` is set, when the packet was meant for us; known ones
+    /// are updated either way. Returns the packets that were held for `ip`.
+    pub(crate) fn learn(
+        &mut self,
+        ip: Ipv4Addr,
+        mac: Mac,
+        insert: bool,
+        now: Instant,
+    ) -> Vec<Vec<u8>> {
+        if !insert && !self.entries.contains_key(&ip) {
+            return Vec::new();
+        }
+        let resolved = Entry::Resolved {
+            mac,
+            expires: now + TIMEOUT,
+        };
+        match self.entries.insert(ip, resolved) {
+            Some(Entry::Resolving { held, .. }) => held.into(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The requests to send again at `now`, as pairs of the address to resolve and the one to
+    /// send from; addresses that did not answer are given up on.
+    pub(crate) fn on_tick(&mut self, now: Instant) -> Vec<(Ipv4Addr, Ipv4Addr)> {
+        let mut resend = Vec::new();
+        self.entries.retain(|&ip, entry| match entry {
+            Entry::Resolved { expires, .. } => *expires > now,
+            Entry::Resolving { next, .. } if *next > now => true,
+            Entry::Resolving {
+                src,
+                requests,
+                next,
+                ..
+            } => {
+                if *requests >= MAX_REQUESTS {
+                    return false;
+                }
+                *requests += 1;
+                *next = now + RETRANSMIT;
+                resend.push((ip, *src));
+                true
+            }
+        });
+        resend
+    }
+
+    /// When requests have to be sent again, if any are outstanding.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.entries
+            .values()
+            .filter_map(|entry| match entry {
+                Entry::Resolving { next, .. } => Some(*next),
+                Entry::Resolved { .. } => None,
+            })
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OURS: Mac = [2, 0, 0, 0, 0, 1];
+    const THEIRS: Mac = [2, 0, 0, 0, 0, 2];
+    const LOCAL: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const PEER: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+    #[test]
+    fn request_bytes() {
+        let bytes = Packet::request(OURS, LOCAL, PEER).to_bytes();
+        assert_eq!(
+            bytes,
+            [
+                0, 1, 8, 0, 6, 4, 0, 1, // ethernet, ipv4, request
+                2, 0, 0, 0, 0, 1, 192, 168, 0, 1, // sender
+                0, 0, 0, 0, 0, 0, 192, 168, 0, 2, // target
+            ]
+        );
+        assert_eq!(
+            Packet::parse(&bytes),
+            Some(Packet::request(OURS, LOCAL, PEER))
+        );
+    }
+
+    #[test]
+    fn reply() {
+        let request = Packet::request(THEIRS, PEER, LOCAL);
+        let reply = request.reply(OURS);
+        assert_eq!(
+            reply,
+            Packet {
+                request: false,
+                sha: OURS,
+                spa: LOCAL,
+                tha: THEIRS,
+                tpa: PEER,
+            }
+        );
+        let bytes = reply.to_bytes();
+        assert_eq!(bytes[6..8], [0, 2]);
+        assert_eq!(Packet::parse(&bytes), Some(reply));
+    }
+
+    #[test]
+    fn parse_ignores_others() {
+        let bytes = Packet::request(OURS, LOCAL, PEER).to_bytes();
+        assert_eq!(Packet::parse(&bytes[..PACKET_LEN - 1]), None);
+
+        let mut other = bytes;
+        other[1] = 6; // ieee 802
+        assert_eq!(Packet::parse(&other), None);
+        let mut other = bytes;
+        other[2..4].copy_from_slice(&ether_type::IPV6.to_be_bytes());
+        assert_eq!(Packet::parse(&other), None);
+        let mut other = bytes;
+        other[7] = 3; // rarp request
+        assert_eq!(Packet::parse(&other), None);
+    }
+
+    #[test]
+    fn resolve() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+        assert_eq!(cache.lookup(PEER, now), None);
+
+        // one request for however many packets are held
+        assert!(cache.hold(PEER, LOCAL, vec![1], now));
+        assert!(!cache.hold(PEER, LOCAL, vec![2], now));
+        assert_eq!(cache.lookup(PEER, now), None);
+        assert_eq!(cache.next_deadline(), Some(now + RETRANSMIT));
+
+        assert_eq!(cache.learn(PEER, THEIRS, true, now), [vec![1], vec![2]]);
+        assert_eq!(cache.lookup(PEER, now), Some(THEIRS));
+        assert_eq!(cache.next_deadline(), None);
+
+        // entries time out
+        assert_eq!(cache.lookup(PEER, now + TIMEOUT), None);
+        assert!(cache.hold(PEER, LOCAL, vec![3], now + TIMEOUT));
+    }
+
+    #[test]
+    fn learn_only_inserts_when_asked() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+        assert!(cache.learn(PEER, THEIRS, false, now).is_empty());
+        assert_eq!(cache.lookup(PEER, now), None);
+
+        cache.learn(PEER, THEIRS, true, now);
+        cache.learn(PEER, OURS, false, now);
+        assert_eq!(cache.lookup(PEER, now), Some(OURS));
+    }
+
+    #[test]
+    fn held_packets_are_bounded() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+        for i in 0..MAX_HELD as u8 + 4 {
+            cache.hold(PEER, LOCAL, vec![i], now);
+        }
+        let held = cache.learn(PEER, THEIRS, true, now);
+        assert_eq!(held.len(), MAX_HELD);
+        assert_eq!(held[0], [4]);
+    }
+
+    #[test]
+    fn retransmit_then_give_up() {
+        let mut cache = Cache::default();
+        let mut now = Instant::now();
+        cache.hold(PEER, LOCAL, vec![1], now);
+        assert!(cache.on_tick(now).is_empty());
+
+        for _ in 1..MAX_REQUESTS {
+            now += RETRANSMIT;
+            assert_eq!(cache.on_tick(now), [(PEER, LOCAL)]);
+        }
+        now += RETRANSMIT;
+        assert!(cache.on_tick(now).is_empty());
+        assert_eq!(cache.next_deadline(), None);
+        assert!(cache.learn(PEER, THEIRS, false, now).is_empty());
+    }
+}
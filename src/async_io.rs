@@ -0,0 +1,129 @@
+//! Future-based accept, read and write.
+//!
+//! Tasks register their [`Waker`] in the connection (or listener) state, and `packet_loop` wakes
+//! them when `on_packet` reports the matching readiness or a new connection is queued. With the
+//! `futures-io` feature, [`TcpStream`] also implements `AsyncRead` and `AsyncWrite`.
+
+use std::{
+    future::poll_fn,
+    io,
+    task::{Context, Poll, Waker},
+};
+
+use crate::{TcpListener, TcpStream};
+
+/// Remember the task in `cx`, unless the same task is already registered.
+fn register(slot: &mut Option<Waker>, cx: &Context<'_>) {
+    match slot {
+        Some(w) if w.will_wake(cx.waker()) => {}
+        _ => *slot = Some(cx.waker().clone()),
+    }
+}
+
+impl TcpStream {
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut c = self.socket.c.lock().unwrap();
+        match self.try_read(&mut c, buf)? {
+            Some(nread) => Poll::Ready(Ok(nread)),
+            None => {
+                register(&mut c.read_waker, cx);
+                Poll::Pending
+            }
+        }
+    }
+
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut c = self.socket.c.lock().unwrap();
+        match self.try_write(&mut c, buf)? {
+            Some(nwrite) => Poll::Ready(Ok(nwrite)),
+            None => {
+                register(&mut c.write_waker, cx);
+                Poll::Pending
+            }
+        }
+    }
+
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut c = self.socket.c.lock().unwrap();
+        if self.try_flush(&mut c)? {
+            Poll::Ready(Ok(()))
+        } else {
+            register(&mut c.write_waker, cx);
+            Poll::Pending
+        }
+    }
+
+    pub async fn read_async(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+
+    pub async fn write_async(&mut self, buf: &[u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_write(cx, buf)).await
+    }
+
+    pub async fn flush_async(&mut self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_flush(cx)).await
+    }
+}
+
+impl TcpListener {
+    pub fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<TcpStream>> {
+        let mut cm = self.cm.manager.lock().unwrap();
+        match self.try_accept(&mut cm)? {
+            Some(stream) => Poll::Ready(Ok(stream)),
+            None => {
+                match cm.accept_wakers.get(&self.port) {
+                    Some(w) if w.will_wake(cx.waker()) => {}
+                    _ => {
+                        cm.accept_wakers.insert(self.port, cx.waker().clone());
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+
+    pub async fn accept_async(&mut self) -> io::Result<TcpStream> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+}
+
+#[cfg(feature = "futures-io")]
+mod futures_io_impls {
+    use std::{
+        io,
+        net::Shutdown,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use crate::TcpStream;
+
+    impl futures_io::AsyncRead for TcpStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            TcpStream::poll_read(self.get_mut(), cx, buf)
+        }
+    }
+
+    impl futures_io::AsyncWrite for TcpStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            TcpStream::poll_write(self.get_mut(), cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            TcpStream::poll_flush(self.get_mut(), cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(self.shutdown(Shutdown::Write))
+        }
+    }
+}
@@ -0,0 +1,453 @@
+//! BBR (draft-cardwell-iccrg-bbr-congestion-control): a model of the path, built from the rate
+//! at which ACKs come back and the lowest round-trip time seen, that sets how fast segments are
+//! paced out and how much may be in flight, instead of filling queues until something is lost.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::tcp::wrapping_lt;
+
+/// 2/ln(2): the smallest gain that still doubles the sending rate every round in startup.
+const HIGH_GAIN: f64 = 2.885;
+
+/// The gain on the bandwidth-delay product in probe bandwidth mode.
+const CWND_GAIN: f64 = 2.0;
+
+/// Pacing gains of the phases of probe bandwidth mode, each about a round trip long: probe for
+/// more, drain the queue that may have caused, then cruise.
+const PACING_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+
+/// Rounds a bandwidth sample counts towards the estimate.
+const BW_WINDOW: u64 = 10;
+
+/// How long a minimum RTT sample is trusted before the queue is drained to take a fresh one.
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+
+/// How long the probe RTT mode holds the window at `MIN_CWND_SEGMENTS`.
+const PROBE_RTT_TIME: Duration = Duration::from_millis(200);
+
+/// Segments that keep ACKs flowing whatever the model says.
+const MIN_CWND_SEGMENTS: u32 = 4;
+
+/// Rounds the bandwidth has to stay below 125% of its best so far for the pipe to be full.
+const FULL_BW_ROUNDS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// double the rate every round until the bandwidth stops growing
+    Startup,
+    /// drain the queue startup built up
+    Drain,
+    /// cycle around the estimated bandwidth
+    ProbeBw,
+    /// hold the window low until the queue drained, for a fresh minimum RTT
+    ProbeRtt,
+}
+
+/// What was delivered when a segment was sent, to measure the delivery rate once it is acked.
+#[derive(Debug)]
+struct Sent {
+    /// sequence number after the segment
+    end: u32,
+    delivered: u64,
+    delivered_at: Instant,
+}
+
+/// The state of BBR for one connection; windows are in bytes, rates in bytes per second.
+#[derive(Debug)]
+pub(crate) struct Bbr {
+    mode: Mode,
+    /// bytes acked so far, and when that last changed
+    delivered: u64,
+    delivered_at: Instant,
+    /// in order of sending
+    sent: VecDeque<Sent>,
+    /// round trips so far, and the sequence number that ends the current one once acked
+    round: u64,
+    round_end: Option<u32>,
+    /// the highest delivery rate of the last `BW_WINDOW` rounds, decreasing after the first
+    bw_samples: VecDeque<(u64, f64)>,
+    min_rtt: Option<Duration>,
+    min_rtt_at: Instant,
+    /// the best bandwidth in startup, and the rounds since it last grew by 25%
+    full_bw: f64,
+    full_bw_rounds: u32,
+    filled_pipe: bool,
+    cycle_index: usize,
+    cycle_start: Instant,
+    /// when probe RTT mode may end, once the window has drained
+    probe_rtt_done: Option<Instant>,
+    cwnd: u32,
+}
+
+impl Bbr {
+    pub(crate) fn new(cwnd: u32) -> Self {
+        let now = Instant::now();
+        Bbr {
+            mode: Mode::Startup,
+            delivered: 0,
+            delivered_at: now,
+            sent: VecDeque::new(),
+            round: 0,
+            round_end: None,
+            bw_samples: VecDeque::new(),
+            min_rtt: None,
+            min_rtt_at: now,
+            full_bw: 0.0,
+            full_bw_rounds: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            cycle_start: now,
+            probe_rtt_done: None,
+            cwnd,
+        }
+    }
+
+    /// How many bytes may be in flight.
+    pub(crate) fn window(&self, mss: u32) -> u32 {
+        if self.mode == Mode::ProbeRtt {
+            return self.cwnd.min(MIN_CWND_SEGMENTS * mss);
+        }
+        self.cwnd
+    }
+
+    /// A segment ending at sequence number `end` was sent at `now`, with `flight` bytes in
+    /// flight before it.
+    pub(crate) fn on_sent(&mut self, end: u32, flight: u32, now: Instant) {
+        if flight == 0 {
+            // nothing is coming back that could be part of this sample
+            self.delivered_at = now;
+        }
+        self.sent.push_back(Sent {
+            end,
+            delivered: self.delivered,
+            delivered_at: self.delivered_at,
+        });
+    }
+
+    /// `acked` bytes up to `ackn` were acked at `now`, with `flight` bytes in flight after them;
+    /// `rtt` is how long the last segment they complete took to be acked.
+    pub(crate) fn on_ack(
+        &mut self,
+        ackn: u32,
+        acked: u32,
+        flight: u32,
+        mss: u32,
+        rtt: Option<Duration>,
+        now: Instant,
+    ) {
+        self.delivered += acked as u64;
+        self.delivered_at = now;
+        let mut sample = None;
+        while self
+            .sent
+            .front()
+            .is_some_and(|sent| !wrapping_lt(ackn, sent.end))
+        {
+            sample = self.sent.pop_front();
+        }
+
+        // a round ends once what was sent at its start is acked
+        let round_start = self.round_end.is_none_or(|end| !wrapping_lt(ackn, end));
+        if round_start {
+            self.round += 1;
+            self.round_end = Some(ackn.wrapping_add(flight));
+        }
+        if let Some(sample) = sample {
+            let elapsed = (now - sample.delivered_at).as_secs_f64();
+            if elapsed > 0.0 {
+                self.on_bw((self.delivered - sample.delivered) as f64 / elapsed);
+            }
+        }
+        let min_rtt_expired = now > self.min_rtt_at + MIN_RTT_WINDOW;
+        if let Some(rtt) = rtt {
+            if self.min_rtt.is_none_or(|min| rtt <= min) || min_rtt_expired {
+                self.min_rtt = Some(rtt);
+                self.min_rtt_at = now;
+            }
+        }
+
+        if round_start && self.mode == Mode::Startup {
+            self.check_full_pipe();
+        }
+        if self.mode == Mode::Drain && flight <= self.bdp(1.0).unwrap_or(0) {
+            self.enter_probe_bw(now);
+        }
+        if self.mode == Mode::ProbeBw {
+            self.advance_cycle(flight, now);
+        }
+        if min_rtt_expired && self.mode != Mode::ProbeRtt {
+            self.mode = Mode::ProbeRtt;
+            self.probe_rtt_done = None;
+        }
+        if self.mode == Mode::ProbeRtt {
+            self.on_probe_rtt(flight, mss, now);
+        }
+
+        if let Some(target) = self.bdp(self.cwnd_gain()) {
+            let target = target.max(MIN_CWND_SEGMENTS * mss);
+            if self.filled_pipe {
+                self.cwnd = self.cwnd.saturating_add(acked).min(target);
+            } else if self.cwnd < target {
+                // startup keeps growing the window like slow start, until the model knows better
+                self.cwnd = self.cwnd.saturating_add(acked);
+            }
+        } else {
+            self.cwnd = self.cwnd.saturating_add(acked);
+        }
+    }
+
+    /// A retransmission timeout: the segments in flight are sent again, so their samples would
+    /// not measure anything.
+    pub(crate) fn on_timeout(&mut self) {
+        self.sent.clear();
+    }
+
+    fn on_bw(&mut self, bw: f64) {
+        while self.bw_samples.back().is_some_and(|&(_, best)| best <= bw) {
+            self.bw_samples.pop_back();
+        }
+        self.bw_samples.push_back((self.round, bw));
+        while self
+            .bw_samples
+            .front()
+            .is_some_and(|&(round, _)| round + BW_WINDOW <= self.round)
+        {
+            self.bw_samples.pop_front();
+        }
+    }
+
+    fn btl_bw(&self) -> Option<f64> {
+        self.bw_samples.front().map(|&(_, bw)| bw)
+    }
+
+    /// The bandwidth-delay product times `gain`, once both are known.
+    fn bdp(&self, gain: f64) -> Option<u32> {
+        let bw = self.btl_bw()?;
+        let rtt = self.min_rtt?.as_secs_f64();
+        Some((gain * bw * rtt) as u32)
+    }
+
+    fn pacing_gain(&self) -> f64 {
+        match self.mode {
+            Mode::Startup => HIGH_GAIN,
+            Mode::Drain => 1.0 / HIGH_GAIN,
+            Mode::ProbeBw => PACING_CYCLE[self.cycle_index],
+            Mode::ProbeRtt => 1.0,
+        }
+    }
+
+    fn cwnd_gain(&self) -> f64 {
+        match self.mode {
+            Mode::Startup | Mode::Drain => HIGH_GAIN,
+            Mode::ProbeBw | Mode::ProbeRtt => CWND_GAIN,
+        }
+    }
+
+    /// How fast segments go out, once there is anything to go by.
+    pub(crate) fn pacing_rate(&self) -> Option<f64> {
+        let bw = match self.btl_bw() {
+            Some(bw) => bw,
+            // the initial window over the first RTT sample
+            None => self.cwnd as f64 / self.min_rtt?.as_secs_f64().max(f64::EPSILON),
+        };
+        Some(self.pacing_gain() * bw)
+    }
+
+    /// The pipe is full once the bandwidth stops growing, although startup doubled the rate.
+    fn check_full_pipe(&mut self) {
+        let Some(bw) = self.btl_bw() else {
+            return;
+        };
+        if bw >= self.full_bw * 1.25 {
+            self.full_bw = bw;
+            self.full_bw_rounds = 0;
+            return;
+        }
+        self.full_bw_rounds += 1;
+        if self.full_bw_rounds >= FULL_BW_ROUNDS {
+            self.filled_pipe = true;
+            self.mode = Mode::Drain;
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: Instant) {
+        self.mode = Mode::ProbeBw;
+        // start cruising rather than probing, since the queue has only just drained
+        self.cycle_index = 2;
+        self.cycle_start = now;
+    }
+
+    fn advance_cycle(&mut self, flight: u32, now: Instant) {
+        let Some(min_rtt) = self.min_rtt else {
+            return;
+        };
+        let elapsed = now - self.cycle_start;
+        // draining ends early once the queue is gone
+        let done = elapsed > min_rtt
+            || (PACING_CYCLE[self.cycle_index] < 1.0 && flight <= self.bdp(1.0).unwrap_or(0));
+        if done {
+            self.cycle_index = (self.cycle_index + 1) % PACING_CYCLE.len();
+            self.cycle_start = now;
+        }
+    }
+
+    fn on_probe_rtt(&mut self, flight: u32, mss: u32, now: Instant) {
+        match self.probe_rtt_done {
+            None if flight <= MIN_CWND_SEGMENTS * mss => {
+                self.probe_rtt_done =
+                    Some(now + PROBE_RTT_TIME.max(self.min_rtt.unwrap_or_default()));
+            }
+            Some(done) if now >= done => {
+                self.min_rtt_at = now;
+                if self.filled_pipe {
+                    self.enter_probe_bw(now);
+                } else {
+                    self.mode = Mode::Startup;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: u32 = 1000;
+    const RTT: Duration = Duration::from_millis(10);
+    /// of the bottleneck: a segment per millisecond, so the bandwidth-delay product is ten
+    const RATE: f64 = 1_000_000.0;
+
+    /// A connection over a path of `RATE` and `RTT`.
+    struct Path {
+        bbr: Bbr,
+        now: Instant,
+        nxt: u32,
+        /// every mode the connection went through
+        modes: Vec<Mode>,
+    }
+
+    impl Path {
+        fn new() -> Self {
+            Path {
+                bbr: Bbr::new(4 * MSS),
+                now: Instant::now(),
+                nxt: 0,
+                modes: vec![Mode::Startup],
+            }
+        }
+
+        /// Send a window back to back, and take the ACKs as they leave the bottleneck.
+        fn round(&mut self) {
+            let segments = self.bbr.window(MSS) / MSS;
+            let start = self.now;
+            let una = self.nxt;
+            for _ in 0..segments {
+                self.bbr.on_sent(self.nxt + MSS, self.nxt - una, start);
+                self.nxt += MSS;
+            }
+            for i in 1..=segments {
+                let queued = Duration::from_secs_f64((i * MSS) as f64 / RATE);
+                self.now = start + RTT + queued;
+                let flight = (segments - i) * MSS;
+                self.bbr
+                    .on_ack(una + i * MSS, MSS, flight, MSS, Some(RTT), self.now);
+                if self.modes.last() != Some(&self.bbr.mode) {
+                    self.modes.push(self.bbr.mode);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bandwidth_window() {
+        let mut bbr = Bbr::new(4 * MSS);
+        bbr.on_bw(100.0);
+        bbr.round = 1;
+        bbr.on_bw(50.0);
+        assert_eq!(bbr.btl_bw(), Some(100.0));
+        // still the best of the last BW_WINDOW rounds
+        bbr.round = BW_WINDOW - 1;
+        bbr.on_bw(10.0);
+        assert_eq!(bbr.btl_bw(), Some(100.0));
+        // then the next best takes over
+        bbr.round = BW_WINDOW;
+        bbr.on_bw(10.0);
+        assert_eq!(bbr.btl_bw(), Some(50.0));
+        // and a better sample replaces everything before it
+        bbr.on_bw(70.0);
+        assert_eq!(bbr.btl_bw(), Some(70.0));
+        assert_eq!(bbr.bw_samples.len(), 1);
+    }
+
+    #[test]
+    fn startup_drain_probe_bw() {
+        let mut path = Path::new();
+        let mut rounds = 0;
+        while path.bbr.mode == Mode::Startup {
+            path.round();
+            rounds += 1;
+            assert!(rounds < 20, "startup never ended");
+        }
+        // the bandwidth stopped growing for FULL_BW_ROUNDS rounds
+        assert!(rounds > FULL_BW_ROUNDS);
+        assert!(path.bbr.filled_pipe);
+        path.round();
+        // drain ends as soon as no more than the bandwidth-delay product is in flight
+        assert_eq!(path.modes, [Mode::Startup, Mode::Drain, Mode::ProbeBw]);
+        let bw = path.bbr.btl_bw().unwrap();
+        assert!(bw > RATE / 2.0 && bw <= RATE, "{bw}");
+        // and the window is kept at twice the bandwidth-delay product
+        assert_eq!(path.bbr.window(MSS), path.bbr.bdp(CWND_GAIN).unwrap());
+    }
+
+    #[test]
+    fn probe_bw_cycle() {
+        let mut bbr = Bbr::new(4 * MSS);
+        let now = Instant::now();
+        bbr.min_rtt = Some(RTT);
+        bbr.on_bw(RATE);
+        bbr.enter_probe_bw(now);
+        assert_eq!(bbr.cycle_index, 2);
+        // each phase lasts a round trip
+        bbr.advance_cycle(10 * MSS, now + RTT / 2);
+        assert_eq!(bbr.cycle_index, 2);
+        bbr.advance_cycle(10 * MSS, now + RTT * 2);
+        assert_eq!(bbr.cycle_index, 3);
+        bbr.cycle_index = PACING_CYCLE.len() - 1;
+        bbr.advance_cycle(10 * MSS, now + RTT * 4);
+        assert_eq!(bbr.cycle_index, 0);
+        // except draining, once the queue is gone
+        bbr.cycle_index = 1;
+        bbr.cycle_start = now;
+        bbr.advance_cycle(20 * MSS, now + RTT / 2);
+        assert_eq!(bbr.cycle_index, 1);
+        bbr.advance_cycle(10 * MSS, now + RTT / 2);
+        assert_eq!(bbr.cycle_index, 2);
+    }
+
+    #[test]
+    fn pacing_rate() {
+        let mut bbr = Bbr::new(4 * MSS);
+        assert_eq!(bbr.pacing_rate(), None);
+        // the initial window over the first round trip, at startup's gain
+        bbr.min_rtt = Some(RTT);
+        let rate = bbr.pacing_rate().unwrap();
+        assert!((rate - HIGH_GAIN * 400_000.0).abs() < 1.0, "{rate}");
+
+        bbr.on_bw(RATE);
+        assert_eq!(bbr.pacing_rate(), Some(HIGH_GAIN * RATE));
+        bbr.mode = Mode::Drain;
+        assert_eq!(bbr.pacing_rate(), Some(RATE / HIGH_GAIN));
+        bbr.enter_probe_bw(Instant::now());
+        assert_eq!(bbr.pacing_rate(), Some(RATE));
+        bbr.cycle_index = 0;
+        assert_eq!(bbr.pacing_rate(), Some(1.25 * RATE));
+        bbr.cycle_index = 1;
+        assert_eq!(bbr.pacing_rate(), Some(0.75 * RATE));
+    }
+}
@@ -0,0 +1,587 @@
+//! Congestion control (RFC 5681): slow start, congestion avoidance, fast retransmit, and NewReno
+//! fast recovery (RFC 6582), which limit how much a connection has in flight on top of what the
+//! peer's window allows. How the window grows and shrinks, and whether segments are paced out,
+//! depends on the [`CongestionAlgorithm`], or on a user-supplied [`CongestionControl`].
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bbr::Bbr,
+    cubic::{self, Cubic},
+    ledbat::Ledbat,
+    tcp::wrapping_lt,
+};
+
+/// RFC 5681 S3.2: duplicate ACKs that are taken to mean a segment was lost, not reordered.
+const DUPACK_THRESHOLD: u32 = 3;
+
+/// Paced segments may go out this much before their time, since packet_loop wakes up with
+/// millisecond resolution.
+const PACING_SLACK: Duration = Duration::from_millis(1);
+
+/// How a connection adapts its congestion window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CongestionAlgorithm {
+    /// RFC 5681, RFC 6582: the window grows by a segment every round trip, and is halved on
+    /// loss.
+    #[default]
+    NewReno,
+    /// RFC 8312: the window grows as a cubic function of the time since the last loss, and
+    /// shrinks by 30% on loss, so that it recovers quickly on paths with a large
+    /// bandwidth-delay product.
+    Cubic,
+    /// BBR: the window and the pacing rate follow a model of the path's bandwidth and round-trip
+    /// time instead of loss, so that queues along the path stay short.
+    Bbr,
+    /// RFC 6817: a scavenger for background transfers, which backs off as soon as it sees
+    /// queues build up, and so yields to other traffic.
+    Ledbat,
+}
+
+/// A congestion control algorithm supplied by the application, set with
+/// [`TcpStream::set_congestion_control`](crate::TcpStream::set_congestion_control).
+///
+/// The stack still detects loss, and retransmits what was lost, as in RFC 5681 and RFC 6582;
+/// the controller only decides how much may be in flight, and how fast it is sent. Windows are
+/// in bytes, and all hooks run on the thread that processes packets, so they should not block.
+pub trait CongestionControl: fmt::Debug + Send {
+    /// `acked` new bytes were acknowledged, leaving `flight` bytes in flight, in segments of
+    /// `mss` bytes; `rtt` is how long the last segment they complete took to be acked.
+    fn on_ack(&mut self, acked: u32, flight: u32, mss: u32, rtt: Option<Duration>);
+
+    /// Duplicate ACKs showed that a segment was lost with `flight` bytes in flight; it is sent
+    /// again right away.
+    fn on_loss(&mut self, flight: u32, mss: u32);
+
+    /// The retransmission timer expired with `flight` bytes in flight; the oldest of them is
+    /// sent again, the rest as the window allows.
+    fn on_timeout(&mut self, flight: u32, mss: u32);
+
+    /// The peer echoed a congestion mark (RFC 3168) with `flight` bytes in flight; nothing was
+    /// lost. Treated as a loss by default.
+    fn on_ecn(&mut self, flight: u32, mss: u32) {
+        self.on_loss(flight, mss);
+    }
+
+    /// A segment of `len` bytes was sent, new or again, with `flight` bytes in flight before it.
+    fn on_sent(&mut self, len: u32, flight: u32) {
+        let _ = (len, flight);
+    }
+
+    /// How many bytes may be in flight.
+    fn window(&self) -> u32;
+
+    /// How many bytes per second to pace segments out at, if not as fast as the window allows.
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// The state of the algorithm a connection uses beyond its window.
+#[derive(Debug)]
+enum Growth {
+    NewReno,
+    Cubic(Box<Cubic>),
+    Bbr(Box<Bbr>),
+    Ledbat(Box<Ledbat>),
+    Custom(Box<dyn CongestionControl>),
+}
+
+impl Growth {
+    fn new(algorithm: CongestionAlgorithm, cwnd: u32) -> Self {
+        match algorithm {
+            CongestionAlgorithm::NewReno => Growth::NewReno,
+            CongestionAlgorithm::Cubic => Growth::Cubic(Box::default()),
+            CongestionAlgorithm::Bbr => Growth::Bbr(Box::new(Bbr::new(cwnd))),
+            CongestionAlgorithm::Ledbat => Growth::Ledbat(Box::default()),
+        }
+    }
+}
+
+/// What the ACK that was just processed asks of the sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    None,
+    /// send the oldest unacknowledged segment again, without waiting for its timeout
+    Retransmit,
+}
+
+/// The congestion window of one connection, in bytes.
+#[derive(Debug)]
+pub(crate) struct Congestion {
+    cwnd: u32,
+    ssthresh: u32,
+    /// duplicate ACKs in a row
+    dupacks: u32,
+    /// RFC 6582 S3.2: SND.NXT when fast recovery last started, which it lasts until acked, or
+    /// when the window was last reduced for a congestion mark (RFC 3168 S6.1.2)
+    recover: u32,
+    in_recovery: bool,
+    /// RFC 5681 S3.1: bytes acked in congestion avoidance that have not grown `cwnd` yet
+    acked: u32,
+    /// set by a retransmission timeout until new data is acked, so that timing out again on
+    /// the same segment does not lower `ssthresh` once more
+    timed_out: bool,
+    /// the built-in algorithm, which a controller may have taken over from
+    algorithm: CongestionAlgorithm,
+    growth: Growth,
+    /// when the next segment is due, if they are paced out
+    next_send: Option<Instant>,
+}
+
+impl Congestion {
+    /// A connection that starts sending at sequence number `iss`, in segments of `mss` bytes.
+    pub(crate) fn new(iss: u32, mss: usize, algorithm: CongestionAlgorithm) -> Self {
+        let cwnd = initial_window(mss);
+        Congestion {
+            cwnd,
+            // RFC 5681 S3.1: arbitrarily high, so that slow start ends by loss
+            ssthresh: u32::MAX,
+            dupacks: 0,
+            recover: iss,
+            in_recovery: false,
+            acked: 0,
+            timed_out: false,
+            algorithm,
+            growth: Growth::new(algorithm, cwnd),
+            next_send: None,
+        }
+    }
+
+    /// The peer announced its segment size before anything was sent, which the initial window
+    /// depends on.
+    pub(crate) fn set_mss(&mut self, mss: usize) {
+        self.cwnd = initial_window(mss);
+        if let Growth::Bbr(bbr) = &mut self.growth {
+            **bbr = Bbr::new(self.cwnd);
+        }
+    }
+
+    /// The built-in algorithm, or the one a [`CongestionControl`] took over from.
+    pub(crate) fn algorithm(&self) -> CongestionAlgorithm {
+        self.algorithm
+    }
+
+    /// Whether a [`CongestionControl`] is in charge of the window.
+    pub(crate) fn is_custom(&self) -> bool {
+        matches!(self.growth, Growth::Custom(_))
+    }
+
+    /// Switch to `algorithm`, which takes over the window as it is.
+    pub(crate) fn set_algorithm(&mut self, algorithm: CongestionAlgorithm) {
+        if self.algorithm != algorithm || self.is_custom() {
+            self.algorithm = algorithm;
+            self.growth = Growth::new(algorithm, self.cwnd);
+            self.acked = 0;
+            self.next_send = None;
+        }
+    }
+
+    /// Hand the window over to `controller`.
+    pub(crate) fn set_controller(&mut self, controller: Box<dyn CongestionControl>) {
+        self.growth = Growth::Custom(controller);
+        self.acked = 0;
+        self.next_send = None;
+    }
+
+    /// How many bytes may be in flight, in segments of `mss` bytes.
+    pub(crate) fn window(&self, mss: usize) -> u32 {
+        match &self.growth {
+            // loss recovery still conserves packets, and a timeout starts over from one segment
+            Growth::Bbr(bbr) if self.in_recovery || self.timed_out => {
+                bbr.window(mss as u32).min(self.cwnd)
+            }
+            Growth::Bbr(bbr) => bbr.window(mss as u32),
+            Growth::Custom(controller) => controller.window(),
+            Growth::NewReno | Growth::Cubic(_) | Growth::Ledbat(_) => self.cwnd,
+        }
+    }
+
+    /// How many bytes per second segments are paced out at, if the algorithm paces them.
+    fn pacing_rate(&self) -> Option<f64> {
+        match &self.growth {
+            Growth::Bbr(bbr) => bbr.pacing_rate(),
+            Growth::Custom(controller) => controller.pacing_rate().map(|rate| rate as f64),
+            Growth::NewReno | Growth::Cubic(_) | Growth::Ledbat(_) => None,
+        }
+        .filter(|rate| *rate > 0.0)
+    }
+
+    /// When the next segment may be sent, if it has to wait.
+    pub(crate) fn send_at(&self) -> Option<Instant> {
+        self.next_send.and_then(|at| at.checked_sub(PACING_SLACK))
+    }
+
+    /// A segment of `len` bytes up to `end` was sent, with `flight` bytes in flight before it.
+    pub(crate) fn on_sent(&mut self, end: u32, len: usize, flight: u32) {
+        let now = Instant::now();
+        match &mut self.growth {
+            Growth::Bbr(bbr) => bbr.on_sent(end, flight, now),
+            Growth::Custom(controller) => controller.on_sent(len as u32, flight),
+            Growth::NewReno | Growth::Cubic(_) | Growth::Ledbat(_) => {}
+        }
+        if let Some(rate) = self.pacing_rate() {
+            let start = self.next_send.map_or(now, |at| at.max(now));
+            self.next_send = Some(start + Duration::from_secs_f64(len as f64 / rate));
+        }
+    }
+
+    /// The peer acknowledged `acked` new bytes, up to `ackn`, with `flight` bytes still in
+    /// flight after them; `rtt` is how long the last segment they complete took to be acked.
+    pub(crate) fn on_ack(
+        &mut self,
+        ackn: u32,
+        acked: u32,
+        flight: u32,
+        mss: usize,
+        rtt: Option<Duration>,
+    ) -> Action {
+        let mss = mss as u32;
+        match (&mut self.growth, rtt) {
+            (Growth::Cubic(cubic), Some(rtt)) => cubic.on_rtt(rtt),
+            (Growth::Bbr(bbr), _) => bbr.on_ack(ackn, acked, flight, mss, rtt, Instant::now()),
+            (Growth::Ledbat(ledbat), Some(rtt)) => ledbat.on_rtt(rtt, Instant::now()),
+            (Growth::Custom(controller), _) => controller.on_ack(acked, flight, mss, rtt),
+            _ => {}
+        }
+        self.dupacks = 0;
+        self.timed_out = false;
+        if self.in_recovery {
+            if wrapping_lt(ackn, self.recover) {
+                // RFC 6582 S3.2 step 5: a partial ACK, so the segment after it was lost as well;
+                // deflate by what was acked, but leave room for the retransmission
+                self.cwnd = self.cwnd.saturating_sub(acked);
+                if acked >= mss {
+                    self.cwnd += mss;
+                }
+                return Action::Retransmit;
+            }
+            // step 6: everything that was in flight when the loss was detected made it
+            self.in_recovery = false;
+            self.cwnd = self.ssthresh.min(flight.max(mss) + mss);
+            self.acked = 0;
+            return Action::None;
+        }
+        if self.cwnd < self.ssthresh {
+            // slow start: one segment per ACK, at most (RFC 3465 with L = 1)
+            self.cwnd = self.cwnd.saturating_add(acked.min(mss));
+            // only the initial slow start, which has no idea of the path yet (RFC 9406 S4.2)
+            let leave = self.ssthresh == u32::MAX
+                && match &mut self.growth {
+                    Growth::Cubic(cubic) => {
+                        let nxt = ackn.wrapping_add(flight);
+                        cubic.leave_slow_start(ackn, nxt, rtt, self.cwnd, mss)
+                    }
+                    // RFC 6817 S2.4.2: no faster than TCP, and not beyond the target delay
+                    Growth::Ledbat(ledbat) => ledbat.leave_slow_start(),
+                    Growth::NewReno | Growth::Bbr(_) | Growth::Custom(_) => false,
+                };
+            if leave {
+                self.ssthresh = self.cwnd;
+            }
+            return Action::None;
+        }
+        match &mut self.growth {
+            Growth::NewReno => {
+                // congestion avoidance: one segment per window
+                self.acked += acked;
+                if self.acked >= self.cwnd {
+                    self.acked -= self.cwnd;
+                    self.cwnd = self.cwnd.saturating_add(mss);
+                }
+            }
+            Growth::Cubic(cubic) => {
+                self.cwnd = cubic.on_ack(self.cwnd, acked, mss, Instant::now());
+            }
+            Growth::Ledbat(ledbat) => {
+                self.cwnd = ledbat.on_ack(self.cwnd, acked, flight, mss);
+            }
+            // the model sets the window
+            Growth::Bbr(_) | Growth::Custom(_) => {}
+        }
+        Action::None
+    }
+
+    /// A duplicate ACK up to `ackn` arrived (RFC 5681 S2), with `flight` bytes in flight up to
+    /// `nxt`.
+    pub(crate) fn on_dupack(&mut self, ackn: u32, nxt: u32, flight: u32, mss: usize) -> Action {
+        let mss = mss as u32;
+        self.dupacks += 1;
+        if self.in_recovery {
+            // RFC 5681 S3.2 step 4: another segment has left the network
+            self.cwnd = self.cwnd.saturating_add(mss);
+            return Action::None;
+        }
+        // RFC 6582 S4.1: duplicate ACKs for what was in flight when the last recovery started
+        // mean nothing new, e.g. after a timeout
+        if self.dupacks != DUPACK_THRESHOLD || wrapping_lt(ackn, self.recover) {
+            return Action::None;
+        }
+        // RFC 5681 S3.2 steps 2 and 3
+        match &mut self.growth {
+            Growth::Cubic(cubic) => cubic.on_loss(self.cwnd),
+            Growth::Custom(controller) => controller.on_loss(flight, mss),
+            Growth::NewReno | Growth::Bbr(_) | Growth::Ledbat(_) => {}
+        }
+        self.ssthresh = self.reduced(flight, mss);
+        self.cwnd = self.ssthresh + DUPACK_THRESHOLD * mss;
+        self.recover = nxt;
+        self.in_recovery = true;
+        Action::Retransmit
+    }
+
+    /// An ACK up to `ackn` echoed a congestion mark, with `flight` bytes in flight up to `nxt`.
+    ///
+    /// Returns whether the window was reduced, which happens at most once per window of data
+    /// and not during loss recovery, which reduced it already (RFC 3168 S6.1.2). BBR never
+    /// reduces it, since its model does not take marks into account.
+    pub(crate) fn on_ecn(&mut self, ackn: u32, nxt: u32, flight: u32, mss: usize) -> bool {
+        let mss = mss as u32;
+        if self.in_recovery || wrapping_lt(ackn, self.recover) {
+            return false;
+        }
+        match &mut self.growth {
+            Growth::Cubic(cubic) => cubic.on_loss(self.cwnd),
+            Growth::Custom(controller) => controller.on_ecn(flight, mss),
+            Growth::NewReno | Growth::Ledbat(_) => {}
+            Growth::Bbr(_) => return false,
+        }
+        self.ssthresh = self.reduced(flight, mss);
+        self.cwnd = self.ssthresh;
+        self.acked = 0;
+        // the marks of what is in flight now are the same congestion
+        self.recover = nxt;
+        true
+    }
+
+    /// The retransmission timer expired, with `flight` bytes in flight up to `nxt`.
+    pub(crate) fn on_timeout(&mut self, nxt: u32, flight: u32, mss: usize) {
+        let mss = mss as u32;
+        if !self.timed_out {
+            // RFC 5681 S3.1 equation (4)
+            self.ssthresh = self.reduced(flight, mss);
+            self.timed_out = true;
+        }
+        match &mut self.growth {
+            Growth::Cubic(cubic) => cubic.on_timeout(),
+            Growth::Bbr(bbr) => bbr.on_timeout(),
+            Growth::Custom(controller) => controller.on_timeout(flight, mss),
+            Growth::NewReno | Growth::Ledbat(_) => {}
+        }
+        self.next_send = None;
+        // the loss window: everything in flight is sent again from slow start
+        self.cwnd = mss;
+        self.acked = 0;
+        self.dupacks = 0;
+        self.in_recovery = false;
+        // RFC 6582 S4: the duplicate ACKs of what is resent now are no reason to recover
+        self.recover = nxt;
+    }
+
+    /// The slow start threshold after congestion with `flight` bytes in flight.
+    fn reduced(&self, flight: u32, mss: u32) -> u32 {
+        let reduced = match self.growth {
+            // RFC 6817 S2.4.2: LEDBAT halves its window like TCP
+            Growth::NewReno | Growth::Ledbat(_) | Growth::Custom(_) => flight / 2,
+            // RFC 8312 S4.5
+            Growth::Cubic(_) => (flight as f64 * cubic::BETA) as u32,
+            // loss is no sign of congestion to BBR, which only keeps recovery from adding to it
+            Growth::Bbr(_) => flight,
+        };
+        reduced.max(2 * mss)
+    }
+}
+
+/// RFC 5681 S3.1 equation (3): two to four segments, depending on their size.
+fn initial_window(mss: usize) -> u32 {
+    let mss = mss as u32;
+    match mss {
+        0..=1095 => 4 * mss,
+        1096..=2190 => 3 * mss,
+        _ => 2 * mss,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    /// A NewReno connection out of slow start, with `flight` bytes in flight up to `nxt`, which
+    /// has just seen the third duplicate ACK for them.
+    fn recovering(nxt: u32, flight: u32) -> Congestion {
+        let mut c = Congestion::new(0, MSS, CongestionAlgorithm::NewReno);
+        c.cwnd = flight;
+        let ackn = nxt.wrapping_sub(flight);
+        assert_eq!(c.on_dupack(ackn, nxt, flight, MSS), Action::None);
+        assert_eq!(c.on_dupack(ackn, nxt, flight, MSS), Action::None);
+        assert_eq!(c.on_dupack(ackn, nxt, flight, MSS), Action::Retransmit);
+        c
+    }
+
+    #[test]
+    fn slow_start() {
+        let mut c = Congestion::new(0, MSS, CongestionAlgorithm::NewReno);
+        assert_eq!(c.window(MSS), 4000);
+        // a segment per ACK, however much it acks
+        c.on_ack(2000, 2000, 2000, MSS, None);
+        assert_eq!(c.window(MSS), 5000);
+        c.on_ack(3000, 1000, 2000, MSS, None);
+        assert_eq!(c.window(MSS), 6000);
+    }
+
+    #[test]
+    fn congestion_avoidance() {
+        let mut c = Congestion::new(0, MSS, CongestionAlgorithm::NewReno);
+        c.cwnd = 4000;
+        c.ssthresh = 4000;
+        for ackn in [1000, 2000, 3000] {
+            c.on_ack(ackn, 1000, 3000, MSS, None);
+            assert_eq!(c.window(MSS), 4000);
+        }
+        // a segment once a window's worth was acked
+        c.on_ack(4000, 1000, 3000, MSS, None);
+        assert_eq!(c.window(MSS), 5000);
+    }
+
+    #[test]
+    fn fast_retransmit() {
+        let c = recovering(10000, 10000);
+        assert!(c.in_recovery);
+        assert_eq!(c.ssthresh, 5000);
+        // RFC 5681 S3.2 step 3: inflated by the segments that left the network
+        assert_eq!(c.window(MSS), 8000);
+    }
+
+    #[test]
+    fn inflate_in_recovery() {
+        let mut c = recovering(10000, 10000);
+        assert_eq!(c.on_dupack(0, 10000, 10000, MSS), Action::None);
+        assert_eq!(c.window(MSS), 9000);
+    }
+
+    #[test]
+    fn partial_ack() {
+        let mut c = recovering(10000, 10000);
+        // acks the retransmission, but not everything up to recover
+        assert_eq!(c.on_ack(3000, 3000, 7000, MSS, None), Action::Retransmit);
+        assert!(c.in_recovery);
+        // deflated by what was acked, plus a segment for the retransmission
+        assert_eq!(c.window(MSS), 6000);
+    }
+
+    #[test]
+    fn recovery_exit() {
+        let mut c = recovering(10000, 10000);
+        c.on_dupack(0, 10000, 10000, MSS);
+        c.on_ack(3000, 3000, 7000, MSS, None);
+        // RFC 6582 S3.2 step 6: a full ACK, which covers recover, ends recovery
+        assert_eq!(c.on_ack(10000, 7000, 0, MSS, None), Action::None);
+        assert!(!c.in_recovery);
+        // deflated to at most ssthresh, and to not much more than was in flight
+        assert_eq!(c.window(MSS), 2000);
+
+        let mut c = recovering(10000, 10000);
+        c.on_ack(12000, 12000, 8000, MSS, None);
+        assert!(!c.in_recovery);
+        assert_eq!(c.window(MSS), 5000);
+        // and congestion avoidance from there
+        c.on_ack(17000, 5000, 8000, MSS, None);
+        assert_eq!(c.window(MSS), 6000);
+    }
+
+    #[test]
+    fn recovery_after_recovery() {
+        let mut c = recovering(10000, 10000);
+        c.on_ack(10000, 10000, 4000, MSS, None);
+        // the segment right after what the last recovery covered is lost as well
+        for _ in 1..DUPACK_THRESHOLD {
+            assert_eq!(c.on_dupack(10000, 14000, 4000, MSS), Action::None);
+        }
+        assert_eq!(c.on_dupack(10000, 14000, 4000, MSS), Action::Retransmit);
+        assert_eq!(c.recover, 14000);
+    }
+
+    #[test]
+    fn no_recovery_after_timeout() {
+        let mut c = Congestion::new(0, MSS, CongestionAlgorithm::NewReno);
+        c.on_timeout(10000, 10000, MSS);
+        assert_eq!(c.window(MSS), 1000);
+        assert_eq!(c.ssthresh, 5000);
+        // RFC 6582 S4.1: the duplicate ACKs for what was in flight when the timer expired
+        for _ in 0..DUPACK_THRESHOLD {
+            assert_eq!(c.on_dupack(0, 11000, 1000, MSS), Action::None);
+        }
+        assert!(!c.in_recovery);
+    }
+
+    #[test]
+    fn ecn_reduces_once_per_window() {
+        let mut c = Congestion::new(0, MSS, CongestionAlgorithm::NewReno);
+        c.cwnd = 10000;
+        assert!(c.on_ecn(2000, 10000, 8000, MSS));
+        assert_eq!(c.ssthresh, 4000);
+        assert_eq!(c.window(MSS), 4000);
+        // marks of what was in flight when the window was reduced
+        assert!(!c.on_ecn(5000, 10000, 5000, MSS));
+        assert_eq!(c.window(MSS), 4000);
+        // a mark of what was sent after is new congestion
+        assert!(c.on_ecn(10000, 14000, 4000, MSS));
+        assert_eq!(c.window(MSS), 2000);
+    }
+
+    #[test]
+    fn no_ecn_reduction_in_recovery() {
+        let mut c = recovering(10000, 10000);
+        let window = c.window(MSS);
+        assert!(!c.on_ecn(0, 10000, 10000, MSS));
+        assert_eq!(c.window(MSS), window);
+    }
+
+    #[test]
+    fn bbr_ignores_ecn() {
+        let mut c = Congestion::new(0, MSS, CongestionAlgorithm::Bbr);
+        c.cwnd = 10000;
+        let window = c.window(MSS);
+        assert!(!c.on_ecn(2000, 10000, 8000, MSS));
+        assert_eq!(c.cwnd, 10000);
+        assert_eq!(c.window(MSS), window);
+    }
+
+    #[derive(Debug)]
+    struct Fixed;
+
+    impl CongestionControl for Fixed {
+        fn on_ack(&mut self, _: u32, _: u32, _: u32, _: Option<Duration>) {}
+        fn on_loss(&mut self, _: u32, _: u32) {}
+        fn on_timeout(&mut self, _: u32, _: u32) {}
+        fn window(&self) -> u32 {
+            1234
+        }
+    }
+
+    #[test]
+    fn custom_and_back() {
+        let mut c = Congestion::new(0, MSS, CongestionAlgorithm::Cubic);
+        c.set_controller(Box::new(Fixed));
+        assert!(c.is_custom());
+        assert_eq!(c.algorithm(), CongestionAlgorithm::Cubic);
+        assert_eq!(c.window(MSS), 1234);
+        // even to the algorithm the controller took over from
+        c.set_algorithm(CongestionAlgorithm::Cubic);
+        assert!(!c.is_custom());
+        assert_eq!(c.window(MSS), 4000);
+    }
+
+    #[test]
+    fn timeout_lowers_ssthresh_once() {
+        let mut c = Congestion::new(0, MSS, CongestionAlgorithm::NewReno);
+        c.on_timeout(10000, 10000, MSS);
+        c.on_timeout(10000, 1000, MSS);
+        assert_eq!(c.ssthresh, 5000);
+    }
+}
@@ -0,0 +1,249 @@
+//! CUBIC (RFC 8312): congestion avoidance that grows the window as a cubic function of the time
+//! since the last congestion event, and HyStart (RFC 9406) to leave slow start before it
+//! overshoots.
+
+use std::time::{Duration, Instant};
+
+use crate::tcp::wrapping_lt;
+
+/// RFC 8312 S5.1: how aggressively the window grows.
+const C: f64 = 0.4;
+
+/// RFC 8312 S5.1: what the window is multiplied by on a congestion event.
+pub(crate) const BETA: f64 = 0.7;
+
+/// RFC 8312 S4.2: the growth of a Reno flow with the same `BETA`, in segments per round trip.
+const ALPHA: f64 = 3.0 * (1.0 - BETA) / (1.0 + BETA);
+
+/// RFC 9406 S4.3: slow start is only left early once the window has this many segments.
+const HYSTART_LOW_WINDOW: u32 = 16;
+
+/// RFC 9406 S4.3: RTT samples taken in a round before its delay is compared to the last one.
+const HYSTART_SAMPLES: u32 = 8;
+
+/// RFC 9406 S4.3: bounds of the delay increase that ends slow start.
+const HYSTART_MIN_ETA: Duration = Duration::from_millis(4);
+const HYSTART_MAX_ETA: Duration = Duration::from_millis(16);
+
+/// The state of CUBIC for one connection; windows are in bytes.
+#[derive(Debug, Default)]
+pub(crate) struct Cubic {
+    /// the window before the last congestion event, if it was not a timeout
+    w_max: f64,
+    /// when the current congestion avoidance stage started, if one has
+    epoch: Option<Instant>,
+    /// the window the cubic function plateaus at, and the time it reaches it after `epoch`
+    origin: f64,
+    k: f64,
+    /// RFC 8312 S4.2: the window a Reno flow would have by now
+    w_est: f64,
+    /// growth of less than a byte, carried over to the next ACK
+    carry: f64,
+    min_rtt: Option<Duration>,
+    hystart: HyStart,
+}
+
+/// Per-round RTT samples of slow start (RFC 9406).
+#[derive(Debug, Default)]
+struct HyStart {
+    /// the round ends once this is acked
+    end: Option<u32>,
+    samples: u32,
+    min_rtt: Option<Duration>,
+    last_min_rtt: Option<Duration>,
+}
+
+impl Cubic {
+    /// A round-trip time measured from an ACK.
+    pub(crate) fn on_rtt(&mut self, rtt: Duration) {
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+    }
+
+    /// Whether the initial slow start should end, now that everything up to `ackn` is acked,
+    /// measuring `rtt`, and `nxt` is the next sequence number to send, with a window of `cwnd`.
+    pub(crate) fn leave_slow_start(
+        &mut self,
+        ackn: u32,
+        nxt: u32,
+        rtt: Option<Duration>,
+        cwnd: u32,
+        mss: u32,
+    ) -> bool {
+        let hystart = &mut self.hystart;
+        if hystart.end.is_none_or(|end| !wrapping_lt(ackn, end)) {
+            // a new round starts with the segments sent from now on
+            hystart.end = Some(nxt);
+            hystart.last_min_rtt = hystart.min_rtt.take();
+            hystart.samples = 0;
+        }
+        if let Some(rtt) = rtt {
+            hystart.samples += 1;
+            hystart.min_rtt = Some(hystart.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        }
+        if cwnd < HYSTART_LOW_WINDOW * mss || hystart.samples < HYSTART_SAMPLES {
+            return false;
+        }
+        let (Some(last), Some(current)) = (hystart.last_min_rtt, hystart.min_rtt) else {
+            return false;
+        };
+        // RFC 9406 S4.3: queues are building up, so the window is about as large as the path
+        let eta = (last / 8).clamp(HYSTART_MIN_ETA, HYSTART_MAX_ETA);
+        current >= last + eta
+    }
+
+    /// The window after `acked` bytes were acked at `now` in congestion avoidance with a
+    /// window of `cwnd` (RFC 8312 S4.1 to S4.4).
+    pub(crate) fn on_ack(&mut self, cwnd: u32, acked: u32, mss: u32, now: Instant) -> u32 {
+        let (cwnd, acked, mss) = (cwnd as f64, acked as f64, mss as f64);
+        let epoch = *self.epoch.get_or_insert_with(|| {
+            if self.w_max <= cwnd {
+                // after a timeout, or when the window grew beyond w_max anyway
+                self.k = 0.0;
+                self.origin = cwnd;
+            } else {
+                self.k = ((self.w_max - cwnd) / mss / C).cbrt();
+                self.origin = self.w_max;
+            }
+            self.w_est = cwnd;
+            self.carry = 0.0;
+            now
+        });
+        let w_cubic = |t: f64| self.origin + C * (t - self.k).powi(3) * mss;
+        let t = (now - epoch).as_secs_f64();
+
+        self.w_est += ALPHA * acked * mss / cwnd;
+        if w_cubic(t) < self.w_est {
+            // the TCP-friendly region, where a Reno flow would grow faster
+            return self.w_est.max(cwnd) as u32;
+        }
+        // the concave and convex regions: where the window should be one round trip from now
+        let rtt = self.min_rtt.unwrap_or_default().as_secs_f64();
+        let target = w_cubic(t + rtt).min(1.5 * cwnd);
+        if target <= cwnd {
+            return cwnd as u32;
+        }
+        self.carry += (target - cwnd) / cwnd * acked;
+        let grow = self.carry.floor();
+        self.carry -= grow;
+        (cwnd + grow) as u32
+    }
+
+    /// A congestion event with a window of `cwnd`, detected by duplicate ACKs.
+    pub(crate) fn on_loss(&mut self, cwnd: u32) {
+        let cwnd = cwnd as f64;
+        // RFC 8312 S4.6: fast convergence, so that flows with a large window leave room for
+        // new ones
+        self.w_max = if cwnd < self.w_max {
+            cwnd * (1.0 + BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.epoch = None;
+    }
+
+    /// A retransmission timeout: the next congestion avoidance stage grows from wherever the
+    /// window is then (RFC 8312 S4.7).
+    pub(crate) fn on_timeout(&mut self) {
+        self.w_max = 0.0;
+        self.epoch = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: u32 = 1000;
+
+    /// RFC 8312 S4.1 equation (1), in bytes, `t` seconds into congestion avoidance after a
+    /// loss at `w_max` bytes that reduced the window to `BETA * w_max`.
+    fn w_cubic(w_max: f64, t: f64) -> f64 {
+        let k = (w_max * (1.0 - BETA) / MSS as f64 / C).cbrt();
+        w_max + C * (t - k).powi(3) * MSS as f64
+    }
+
+    /// The window after acking a whole window at `t` seconds into the stage, which reaches
+    /// whatever the curve is at then.
+    fn ack_window(cubic: &mut Cubic, cwnd: u32, start: Instant, t: f64) -> u32 {
+        cubic.on_ack(cwnd, cwnd, MSS, start + Duration::from_secs_f64(t))
+    }
+
+    #[test]
+    fn window_curve() {
+        let w_max = 100_000.0;
+        let mut cubic = Cubic::default();
+        cubic.on_loss(w_max as u32);
+        let start = Instant::now();
+        let mut cwnd = (w_max * BETA) as u32;
+        cwnd = cubic.on_ack(cwnd, MSS, MSS, start);
+
+        let k = (w_max * (1.0 - BETA) / MSS as f64 / C).cbrt();
+        let mut last = cwnd;
+        // concave up to w_max, where it plateaus at k, then convex beyond it
+        for t in [1.0, 2.0, 3.0, 4.0, k, 5.0, 6.0, 7.0] {
+            cwnd = ack_window(&mut cubic, cwnd, start, t);
+            let expected = w_cubic(w_max, t);
+            assert!(
+                (cwnd as f64 - expected).abs() <= 1.0,
+                "{cwnd} at {t}s, expected {expected}"
+            );
+            assert!(cwnd >= last);
+            last = cwnd;
+        }
+        assert!((w_cubic(w_max, k) - w_max).abs() < 1.0);
+    }
+
+    #[test]
+    fn grows_at_most_by_half() {
+        let mut cubic = Cubic::default();
+        cubic.on_loss(100_000);
+        let start = Instant::now();
+        let cwnd = cubic.on_ack(70_000, MSS, MSS, start);
+        // far out on the convex side, the curve is way above the window
+        assert_eq!(ack_window(&mut cubic, cwnd, start, 60.0), cwnd + cwnd / 2);
+    }
+
+    #[test]
+    fn fast_convergence() {
+        let mut cubic = Cubic::default();
+        cubic.on_loss(100_000);
+        // lost again before the window got back to where it was
+        cubic.on_loss(80_000);
+        assert_eq!(cubic.w_max, 80_000.0 * (1.0 + BETA) / 2.0);
+    }
+
+    #[test]
+    fn tcp_friendly_after_timeout() {
+        let mut cubic = Cubic::default();
+        cubic.on_loss(100_000);
+        cubic.on_timeout();
+        let start = Instant::now();
+        let cwnd = cubic.on_ack(10_000, MSS, MSS, start);
+        // the curve starts out flat from the window, where Reno grows faster: ALPHA segments
+        // per window acked, over both ACKs
+        let cwnd = ack_window(&mut cubic, cwnd, start, 0.1);
+        let reno = 10_000.0 + ALPHA * MSS as f64 * (MSS as f64 / 10_000.0 + 1.0);
+        assert_eq!(cwnd, reno as u32);
+    }
+
+    #[test]
+    fn hystart() {
+        let mut cubic = Cubic::default();
+        let rtt = Duration::from_millis(100);
+        let cwnd = HYSTART_LOW_WINDOW * MSS;
+        let mut ackn = 0;
+        // a round of samples at the base RTT, which ends once the last of them is acked
+        let end = (HYSTART_SAMPLES + 1) * MSS;
+        for _ in 0..HYSTART_SAMPLES {
+            ackn += MSS;
+            assert!(!cubic.leave_slow_start(ackn, end, Some(rtt), cwnd, MSS));
+        }
+        // the next round sees the delay grow by more than an eighth
+        let mut left = false;
+        for _ in 0..HYSTART_SAMPLES {
+            ackn += MSS;
+            left = cubic.leave_slow_start(ackn, 40 * MSS, Some(rtt * 5 / 4), cwnd, MSS);
+        }
+        assert!(left);
+    }
+}
@@ -0,0 +1,174 @@
+//! Configuration of the tun device through the same ioctls `ip` and `ifconfig` use.
+//!
+//! Everything but reading the MTU needs `CAP_NET_ADMIN`.
+
+use std::{
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use nix::libc;
+
+// TUNSETPERSIST = _IOW('T', 203, int)
+nix::ioctl_write_int!(tun_set_persist, b'T', 203);
+
+/// Keep the device around after its descriptor is closed, or let it go away with it.
+pub(crate) fn set_persist(tun: RawFd, persist: bool) -> io::Result<()> {
+    // SAFETY: tun is an open tun descriptor, and TUNSETPERSIST takes its argument by value
+    unsafe { tun_set_persist(tun, persist as _) }?;
+    Ok(())
+}
+
+/// A socket to issue interface ioctls on, together with the request for one interface.
+struct Control {
+    sock: OwnedFd,
+    name: [libc::c_char; libc::IFNAMSIZ],
+}
+
+impl Control {
+    fn new(name: &str) -> io::Result<Self> {
+        Self::with_family(name, libc::AF_INET)
+    }
+
+    /// IPv6 addresses can only be configured through an `AF_INET6` socket.
+    fn with_family(name: &str, family: libc::c_int) -> io::Result<Self> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "interface name too long",
+            ));
+        }
+        let mut ifr_name = [0; libc::IFNAMSIZ];
+        for (dst, src) in ifr_name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+
+        // SAFETY: plain socket(2) call, the result is checked below
+        let sock = unsafe { libc::socket(family, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Control {
+            // SAFETY: socket just returned this descriptor, and nothing else owns it
+            sock: unsafe { OwnedFd::from_raw_fd(sock) },
+            name: ifr_name,
+        })
+    }
+
+    fn ifreq(&self) -> libc::ifreq {
+        // SAFETY: ifreq is plain old data, for which all zeroes is a valid value
+        let mut ifr: libc::ifreq = unsafe { mem::zeroed() };
+        ifr.ifr_name = self.name;
+        ifr
+    }
+
+    /// Issue `request`, which must take a pointer to a `T`.
+    fn ioctl<T>(&self, request: libc::c_ulong, arg: &mut T) -> io::Result<()> {
+        // SAFETY: callers pass the argument type the request expects
+        if unsafe { libc::ioctl(self.sock.as_raw_fd(), request as _, arg as *mut T) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn sockaddr(addr: Ipv4Addr) -> libc::sockaddr {
+    let sin = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(addr).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: sockaddr_in and sockaddr have the same size, and the kernel reads the former
+    // through the latter based on sin_family
+    unsafe { mem::transmute::<libc::sockaddr_in, libc::sockaddr>(sin) }
+}
+
+/// Assign `addr` with a `prefix` bit netmask to the interface `name`.
+pub(crate) fn set_address(name: &str, addr: IpAddr, prefix: u8) -> io::Result<()> {
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix > max_prefix {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "prefix length too long for the address family",
+        ));
+    }
+    match addr {
+        IpAddr::V4(addr) => set_v4_address(name, addr, prefix),
+        IpAddr::V6(addr) => set_v6_address(name, addr, prefix),
+    }
+}
+
+fn set_v4_address(name: &str, addr: Ipv4Addr, prefix: u8) -> io::Result<()> {
+    let netmask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+
+    let ctl = Control::new(name)?;
+    let mut ifr = ctl.ifreq();
+    ifr.ifr_ifru.ifru_addr = sockaddr(addr);
+    ctl.ioctl(libc::SIOCSIFADDR, &mut ifr)?;
+    let mut ifr = ctl.ifreq();
+    ifr.ifr_ifru.ifru_netmask = sockaddr(Ipv4Addr::from(netmask));
+    ctl.ioctl(libc::SIOCSIFNETMASK, &mut ifr)
+}
+
+/// Add `addr` to the interface, like `ip -6 addr add`; IPv6 interfaces can have several.
+fn set_v6_address(name: &str, addr: Ipv6Addr, prefix: u8) -> io::Result<()> {
+    let ctl = Control::with_family(name, libc::AF_INET6)?;
+    let mut ifr = ctl.ifreq();
+    ctl.ioctl(libc::SIOCGIFINDEX, &mut ifr)?;
+    let mut ifr6 = libc::in6_ifreq {
+        ifr6_addr: libc::in6_addr {
+            s6_addr: addr.octets(),
+        },
+        ifr6_prefixlen: prefix as u32,
+        // SAFETY: SIOCGIFINDEX filled in the ifindex member
+        ifr6_ifindex: unsafe { ifr.ifr_ifru.ifru_ifindex },
+    };
+    ctl.ioctl(libc::SIOCSIFADDR, &mut ifr6)
+}
+
+pub(crate) fn set_mtu(name: &str, mtu: u32) -> io::Result<()> {
+    let ctl = Control::new(name)?;
+    let mut ifr = ctl.ifreq();
+    ifr.ifr_ifru.ifru_mtu = libc::c_int::try_from(mtu)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "MTU too large"))?;
+    ctl.ioctl(libc::SIOCSIFMTU, &mut ifr)
+}
+
+pub(crate) fn mtu(name: &str) -> io::Result<u32> {
+    let ctl = Control::new(name)?;
+    let mut ifr = ctl.ifreq();
+    ctl.ioctl(libc::SIOCGIFMTU, &mut ifr)?;
+    // SAFETY: SIOCGIFMTU filled in the mtu member
+    Ok(unsafe { ifr.ifr_ifru.ifru_mtu } as u32)
+}
+
+// from linux/sockios.h
+const SIOCBRADDIF: libc::c_ulong = 0x89a2;
+
+/// Add the interface `name` to the bridge `bridge`, like `ip link set master`.
+pub(crate) fn add_to_bridge(bridge: &str, name: &str) -> io::Result<()> {
+    let ctl = Control::new(name)?;
+    let mut ifr = ctl.ifreq();
+    ctl.ioctl(libc::SIOCGIFINDEX, &mut ifr)?;
+    // SAFETY: SIOCGIFINDEX filled in the ifindex member
+    let index = unsafe { ifr.ifr_ifru.ifru_ifindex };
+
+    let ctl = Control::new(bridge)?;
+    let mut ifr = ctl.ifreq();
+    ifr.ifr_ifru.ifru_ifindex = index;
+    ctl.ioctl(SIOCBRADDIF, &mut ifr)
+}
+
+/// Set the interface `name` administratively up.
+pub(crate) fn set_up(name: &str) -> io::Result<()> {
+    let ctl = Control::new(name)?;
+    let mut ifr = ctl.ifreq();
+    ctl.ioctl(libc::SIOCGIFFLAGS, &mut ifr)?;
+    // SAFETY: SIOCGIFFLAGS filled in the flags member
+    unsafe { ifr.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+    ctl.ioctl(libc::SIOCSIFFLAGS, &mut ifr)
+}
@@ -0,0 +1,238 @@
+//! Explicit Congestion Notification (RFC 3168): routers that would otherwise drop a packet to
+//! signal congestion mark it instead, the receiver echoes the mark back with ECE, and the
+//! sender reduces its window as for a loss, without having to retransmit anything.
+
+use etherparse::{TcpHeader, TcpHeaderSlice};
+
+use crate::ip::EcnCodepoint;
+
+/// The ECN state of one connection.
+#[derive(Debug)]
+pub(crate) struct Ecn {
+    /// before the handshake completes: whether we ask for ECN; after: whether both ends use it
+    enabled: bool,
+    /// RFC 3168 S6.1.3: a CE mark arrived, which ECE echoes until the peer answers with CWR
+    echo: bool,
+    /// RFC 3168 S6.1.2: the window was reduced for an ECE, which the next new data segment
+    /// tells the peer with CWR
+    reduced: bool,
+}
+
+impl Ecn {
+    /// An active open, which asks for ECN in its SYN if `wanted`.
+    pub(crate) fn new(wanted: bool) -> Self {
+        Ecn {
+            enabled: wanted,
+            echo: false,
+            reduced: false,
+        }
+    }
+
+    /// A passive open for `syn`, which uses ECN if `wanted` and the SYN asks for it with both
+    /// ECE and CWR (RFC 3168 S6.1.1).
+    pub(crate) fn accept(wanted: bool, syn: &TcpHeaderSlice) -> Self {
+        Ecn::new(wanted && syn.ece() && syn.cwr())
+    }
+
+    /// The answer to our SYN, which agrees to ECN with ECE alone; both flags are what a host
+    /// that does not know ECN sends back if it echoes the flags it got.
+    pub(crate) fn on_syn_ack(&mut self, syn_ack: &TcpHeaderSlice) {
+        self.enabled &= syn_ack.ece() && !syn_ack.cwr();
+    }
+
+    /// A segment that carried `codepoint` in its IP header arrived (RFC 3168 S6.1.3).
+    pub(crate) fn on_segment(&mut self, tcph: &TcpHeaderSlice, codepoint: EcnCodepoint) {
+        if !self.enabled {
+            return;
+        }
+        if tcph.cwr() {
+            self.echo = false;
+        }
+        if codepoint == EcnCodepoint::Ce {
+            self.echo = true;
+        }
+    }
+
+    /// Whether an ACK with ECE from the peer is to be taken as a congestion signal.
+    pub(crate) fn is_echo(&self, tcph: &TcpHeaderSlice) -> bool {
+        self.enabled && tcph.ece() && !tcph.syn()
+    }
+
+    /// The window was reduced in response to an ECE.
+    pub(crate) fn on_reduced(&mut self) {
+        self.reduced = true;
+    }
+
+    /// Set the ECN flags of `tcp`, which is about to be sent with `new_data` or not, and return
+    /// the codepoint of the IP header it goes out under.
+    ///
+    /// Only new data is ECN-capable: pure ACKs and retransmissions are not, since their loss
+    /// cannot be signalled back (RFC 3168 S6.1.4, S6.1.5), and neither is the handshake.
+    pub(crate) fn mark(&mut self, tcp: &mut TcpHeader, new_data: bool) -> EcnCodepoint {
+        match (tcp.syn, tcp.ack) {
+            (true, false) => {
+                tcp.ece = self.enabled;
+                tcp.cwr = self.enabled;
+                return EcnCodepoint::NotEct;
+            }
+            (true, true) => {
+                tcp.ece = self.enabled;
+                tcp.cwr = false;
+                return EcnCodepoint::NotEct;
+            }
+            _ => {}
+        }
+        tcp.ece = self.echo;
+        tcp.cwr = false;
+        if !self.enabled || !new_data {
+            return EcnCodepoint::NotEct;
+        }
+        if self.reduced {
+            tcp.cwr = true;
+            self.reduced = false;
+        }
+        EcnCodepoint::Ect0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A header with the flags that `set` chose.
+    fn header(set: impl FnOnce(&mut TcpHeader)) -> Vec<u8> {
+        let mut tcp = TcpHeader::new(1, 2, 0, 1000);
+        set(&mut tcp);
+        let mut bytes = Vec::new();
+        tcp.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn slice(bytes: &[u8]) -> TcpHeaderSlice<'_> {
+        TcpHeaderSlice::from_slice(bytes).unwrap()
+    }
+
+    /// Both ends agreed to ECN.
+    fn negotiated() -> Ecn {
+        let syn_ack = header(|tcp| {
+            tcp.syn = true;
+            tcp.ack = true;
+            tcp.ece = true;
+        });
+        let mut ecn = Ecn::new(true);
+        ecn.on_syn_ack(&slice(&syn_ack));
+        assert!(ecn.enabled);
+        ecn
+    }
+
+    #[test]
+    fn active_open() {
+        let mut ecn = Ecn::new(true);
+        let mut syn = TcpHeader::new(1, 2, 0, 1000);
+        syn.syn = true;
+        assert_eq!(ecn.mark(&mut syn, false), EcnCodepoint::NotEct);
+        assert!(syn.ece && syn.cwr);
+        // the peer agrees with ECE alone
+        negotiated();
+
+        // a host that does not know ECN, and reflects the flags of our SYN
+        let reflected = header(|tcp| {
+            tcp.syn = true;
+            tcp.ack = true;
+            tcp.ece = true;
+            tcp.cwr = true;
+        });
+        ecn.on_syn_ack(&slice(&reflected));
+        assert!(!ecn.enabled);
+        // and one that just ignores them
+        let mut ecn = Ecn::new(true);
+        ecn.on_syn_ack(&slice(&header(|tcp| tcp.syn = true)));
+        assert!(!ecn.enabled);
+    }
+
+    #[test]
+    fn passive_open() {
+        let both = header(|tcp| {
+            tcp.syn = true;
+            tcp.ece = true;
+            tcp.cwr = true;
+        });
+        let mut ecn = Ecn::accept(true, &slice(&both));
+        assert!(ecn.enabled);
+        let mut syn_ack = TcpHeader::new(1, 2, 0, 1000);
+        syn_ack.syn = true;
+        syn_ack.ack = true;
+        assert_eq!(ecn.mark(&mut syn_ack, false), EcnCodepoint::NotEct);
+        assert!(syn_ack.ece && !syn_ack.cwr);
+
+        assert!(!Ecn::accept(false, &slice(&both)).enabled);
+        let ece_only = header(|tcp| {
+            tcp.syn = true;
+            tcp.ece = true;
+        });
+        assert!(!Ecn::accept(true, &slice(&ece_only)).enabled);
+    }
+
+    #[test]
+    fn only_new_data_is_capable() {
+        let mut ecn = negotiated();
+        let mut tcp = TcpHeader::new(1, 2, 0, 1000);
+        tcp.ack = true;
+        assert_eq!(ecn.mark(&mut tcp, true), EcnCodepoint::Ect0);
+        assert_eq!(ecn.mark(&mut tcp, false), EcnCodepoint::NotEct);
+
+        let mut ecn = Ecn::new(false);
+        assert_eq!(ecn.mark(&mut tcp, true), EcnCodepoint::NotEct);
+    }
+
+    #[test]
+    fn echo_until_cwr() {
+        let mut ecn = negotiated();
+        let ack = header(|tcp| tcp.ack = true);
+        ecn.on_segment(&slice(&ack), EcnCodepoint::Ce);
+        let mut tcp = TcpHeader::new(1, 2, 0, 1000);
+        tcp.ack = true;
+        ecn.mark(&mut tcp, false);
+        assert!(tcp.ece);
+        // unmarked segments do not end the echo, only CWR does
+        ecn.on_segment(&slice(&ack), EcnCodepoint::Ect0);
+        ecn.mark(&mut tcp, false);
+        assert!(tcp.ece);
+        let cwr = header(|tcp| {
+            tcp.ack = true;
+            tcp.cwr = true;
+        });
+        ecn.on_segment(&slice(&cwr), EcnCodepoint::Ect0);
+        ecn.mark(&mut tcp, false);
+        assert!(!tcp.ece);
+    }
+
+    #[test]
+    fn cwr_after_reduction() {
+        let mut ecn = negotiated();
+        let ece = header(|tcp| {
+            tcp.ack = true;
+            tcp.ece = true;
+        });
+        assert!(ecn.is_echo(&slice(&ece)));
+        ecn.on_reduced();
+        let mut tcp = TcpHeader::new(1, 2, 0, 1000);
+        tcp.ack = true;
+        // not on a pure ACK, but on the next new data, and only once
+        ecn.mark(&mut tcp, false);
+        assert!(!tcp.cwr);
+        ecn.mark(&mut tcp, true);
+        assert!(tcp.cwr);
+        ecn.mark(&mut tcp, true);
+        assert!(!tcp.cwr);
+
+        // ECE on a SYN-ACK agrees to ECN rather than echoing a mark
+        let syn_ack = header(|tcp| {
+            tcp.syn = true;
+            tcp.ack = true;
+            tcp.ece = true;
+        });
+        assert!(!ecn.is_echo(&slice(&syn_ack)));
+        assert!(!Ecn::new(false).is_echo(&slice(&ece)));
+    }
+}
@@ -0,0 +1,47 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use nix::{
+    errno::Errno,
+    sys::eventfd::{eventfd, EfdFlags},
+};
+
+/// An eventfd that becomes readable when a socket is marked ready by `packet_loop`.
+///
+/// This lets applications wait for the stack from epoll, mio or any other OS event loop.
+#[derive(Debug)]
+pub(crate) struct EventFd(OwnedFd);
+
+impl EventFd {
+    pub(crate) fn new() -> io::Result<Self> {
+        let fd = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
+        // SAFETY: eventfd just returned this descriptor, and nothing else owns it
+        Ok(EventFd(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    /// Make the descriptor readable.
+    pub(crate) fn signal(&self) {
+        match nix::unistd::write(self.0.as_raw_fd(), &1u64.to_ne_bytes()) {
+            // EAGAIN means the counter is saturated, which is readable all the same
+            Ok(_) | Err(Errno::EAGAIN) => {}
+            Err(e) => eprintln!("failed to signal eventfd: {e}"),
+        }
+    }
+
+    /// Reset the descriptor so that it is no longer readable.
+    pub(crate) fn drain(&self) {
+        let mut buf = [0u8; 8];
+        match nix::unistd::read(self.0.as_raw_fd(), &mut buf) {
+            Ok(_) | Err(Errno::EAGAIN) => {}
+            Err(e) => eprintln!("failed to drain eventfd: {e}"),
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
@@ -0,0 +1,452 @@
+//! Reassembly of IPv4 fragments (RFC 791 S3.2, RFC 815) before they are handed to TCP or ICMP.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use etherparse::{Ipv4Header, Ipv4HeaderSlice};
+
+/// RFC 1122 S3.3.2: how long the fragments of a datagram are kept waiting for the rest.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Bytes of fragment data held across all datagrams, so that a flood of fragments that never
+/// complete cannot take all our memory.
+const MAX_BYTES: usize = 256 * 1024;
+
+/// Datagrams reassembled at the same time.
+const MAX_DATAGRAMS: usize = 64;
+
+/// The largest datagram, since the total length has 16 bits.
+const MAX_LEN: usize = u16::MAX as usize;
+
+/// RFC 791 S3.2: the fragments of one datagram agree on all of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    id: u16,
+}
+
+/// A datagram whose fragments are arriving.
+#[derive(Debug)]
+struct Datagram {
+    expires: Instant,
+    /// of the first fragment, which becomes the header of the datagram
+    header: Option<Vec<u8>>,
+    /// of the payload, known once the last fragment arrived
+    len: Option<usize>,
+    /// fragment data by offset, none of which overlap
+    parts: BTreeMap<usize, Vec<u8>>,
+    bytes: usize,
+}
+
+/// What became of an inbound fragment.
+#[derive(Debug)]
+pub(crate) enum Outcome {
+    /// the datagram it completed
+    Complete(Vec<u8>),
+    /// held until the rest of its datagram arrives
+    Pending,
+    /// dropped, possibly along with the rest of its datagram
+    Failed,
+}
+
+/// The fragments of the datagrams being reassembled.
+#[derive(Debug, Default)]
+pub(crate) struct Reassembly {
+    datagrams: HashMap<Key, Datagram>,
+    bytes: usize,
+}
+
+impl Reassembly {
+    /// Add `fragment`, a whole IPv4 packet with a valid header, received at `now`.
+    pub(crate) fn insert(&mut self, fragment: &[u8], now: Instant) -> Outcome {
+        let Ok(iph) = Ipv4HeaderSlice::from_slice(fragment) else {
+            return Outcome::Failed;
+        };
+        let header_len = iph.slice().len();
+        let data = &fragment[header_len..iph.total_len() as usize];
+        let offset = iph.fragments_offset() as usize * 8;
+        let end = offset + data.len();
+        let more = iph.more_fragments();
+        // every fragment but the last one carries a multiple of 8 bytes, or the next offset
+        // could not be expressed
+        let aligned = !more || data.len().is_multiple_of(8);
+        if data.is_empty() || !aligned || header_len + end > MAX_LEN {
+            return Outcome::Failed;
+        }
+
+        let key = Key {
+            src: iph.source_addr(),
+            dst: iph.destination_addr(),
+            protocol: iph.protocol(),
+            id: iph.identification(),
+        };
+        if !self.datagrams.contains_key(&key) && self.datagrams.len() >= MAX_DATAGRAMS {
+            return Outcome::Failed;
+        }
+        if self.bytes + data.len() > MAX_BYTES {
+            return Outcome::Failed;
+        }
+        let datagram = self.datagrams.entry(key).or_insert_with(|| Datagram {
+            expires: now + TIMEOUT,
+            header: None,
+            len: None,
+            parts: BTreeMap::new(),
+            bytes: 0,
+        });
+
+        if datagram.duplicates(offset, data) {
+            return Outcome::Pending;
+        }
+        // RFC 5722 (for IPv6, but the attacks are the same): fragments that overlap without
+        // being duplicates could be reassembled into different datagrams by us and by whoever
+        // inspected them on the way, so none of them are trusted
+        let beyond_len = datagram
+            .len
+            .is_some_and(|len| end > len || (!more && end != len));
+        let beyond_last = !more
+            && datagram
+                .parts
+                .last_key_value()
+                .is_some_and(|(&at, p)| at + p.len() > end);
+        if beyond_len || beyond_last || datagram.overlaps(offset, end) {
+            self.remove(&key);
+            return Outcome::Failed;
+        }
+
+        if !more {
+            datagram.len = Some(end);
+        }
+        if offset == 0 {
+            datagram.header = Some(fragment[..header_len].to_vec());
+        }
+        datagram.parts.insert(offset, data.to_vec());
+        datagram.bytes += data.len();
+        self.bytes += data.len();
+        if !datagram.is_complete() {
+            return Outcome::Pending;
+        }
+
+        let datagram = self.remove(&key).expect("just completed");
+        datagram
+            .assemble()
+            .map_or(Outcome::Failed, Outcome::Complete)
+    }
+
+    /// When the next datagram times out, if any are being reassembled.
+    pub(crate) fn next_expiry(&self) -> Option<Instant> {
+        self.datagrams.values().map(|d| d.expires).min()
+    }
+
+    /// Give up on the datagrams that timed out by `now`.
+    ///
+    /// Returns each of them, as its first fragment if that arrived (RFC 792: the sender is only
+    /// told about the timeout when it did).
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<Option<Vec<u8>>> {
+        let expired: Vec<_> = self
+            .datagrams
+            .iter()
+            .filter(|(_, d)| d.expires <= now)
+            .map(|(&key, _)| key)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|key| self.remove(&key))
+            .map(|d| {
+                let header = d.header?;
+                let data = d.parts.get(&0)?;
+                Some([header.as_slice(), data].concat())
+            })
+            .collect()
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Datagram> {
+        let datagram = self.datagrams.remove(key)?;
+        self.bytes -= datagram.bytes;
+        Some(datagram)
+    }
+}
+
+impl Datagram {
+    /// Whether exactly `data` was already received at `offset`.
+    fn duplicates(&self, offset: usize, data: &[u8]) -> bool {
+        self.parts.get(&offset).is_some_and(|p| p == data)
+    }
+
+    /// Whether any part received so far overlaps the bytes from `offset` up to `end`.
+    fn overlaps(&self, offset: usize, end: usize) -> bool {
+        let before = self.parts.range(..=offset).next_back();
+        let after = self.parts.range(offset..).next();
+        before.is_some_and(|(&at, p)| at + p.len() > offset)
+            || after.is_some_and(|(&at, _)| at < end)
+    }
+
+    fn is_complete(&self) -> bool {
+        let Some(len) = self.len else {
+            return false;
+        };
+        let mut next = 0;
+        for (&at, part) in &self.parts {
+            if at != next {
+                return false;
+            }
+            next += part.len();
+        }
+        next == len && self.header.is_some()
+    }
+
+    /// The datagram, under the header of its first fragment, which no longer says it is one.
+    fn assemble(self) -> Option<Vec<u8>> {
+        let (mut iph, _) = Ipv4Header::from_slice(self.header.as_deref()?).ok()?;
+        iph.more_fragments = false;
+        iph.fragments_offset = 0;
+        iph.set_payload_len(self.len?).ok()?;
+        let mut datagram = Vec::with_capacity(iph.header_len() + self.bytes);
+        // also recomputes the header checksum
+        iph.write(&mut datagram).ok()?;
+        for part in self.parts.into_values() {
+            datagram.extend_from_slice(&part);
+        }
+        Some(datagram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::IpNumber;
+
+    use super::*;
+
+    /// A fragment of datagram `id` with `data` at byte `offset`.
+    fn fragment(id: u16, offset: usize, data: &[u8], more: bool) -> Vec<u8> {
+        let mut iph = Ipv4Header::new(
+            data.len() as u16,
+            64,
+            IpNumber::Udp as u8,
+            [10, 0, 0, 1],
+            [10, 0, 0, 2],
+        );
+        iph.identification = id;
+        iph.more_fragments = more;
+        iph.fragments_offset = (offset / 8) as u16;
+        let mut fragment = Vec::new();
+        iph.write(&mut fragment).unwrap();
+        fragment.extend_from_slice(data);
+        fragment
+    }
+
+    fn payload(datagram: &[u8]) -> &[u8] {
+        let iph = Ipv4HeaderSlice::from_slice(datagram).unwrap();
+        assert!(!iph.more_fragments());
+        assert_eq!(iph.fragments_offset(), 0);
+        assert_eq!(iph.total_len() as usize, datagram.len());
+        &datagram[iph.slice().len()..]
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn in_order() {
+        let now = Instant::now();
+        let data = data(40);
+        let mut r = Reassembly::default();
+        assert!(matches!(
+            r.insert(&fragment(1, 0, &data[..16], true), now),
+            Outcome::Pending
+        ));
+        assert!(matches!(
+            r.insert(&fragment(1, 16, &data[16..32], true), now),
+            Outcome::Pending
+        ));
+        let Outcome::Complete(datagram) = r.insert(&fragment(1, 32, &data[32..], false), now)
+        else {
+            panic!("not complete");
+        };
+        assert_eq!(payload(&datagram), data);
+        assert_eq!(r.next_expiry(), None);
+        assert_eq!(r.bytes, 0);
+    }
+
+    #[test]
+    fn out_of_order() {
+        let now = Instant::now();
+        let data = data(40);
+        let mut r = Reassembly::default();
+        assert!(matches!(
+            r.insert(&fragment(1, 32, &data[32..], false), now),
+            Outcome::Pending
+        ));
+        assert!(matches!(
+            r.insert(&fragment(1, 0, &data[..16], true), now),
+            Outcome::Pending
+        ));
+        let Outcome::Complete(datagram) = r.insert(&fragment(1, 16, &data[16..32], true), now)
+        else {
+            panic!("not complete");
+        };
+        assert_eq!(payload(&datagram), data);
+    }
+
+    #[test]
+    fn duplicate() {
+        let now = Instant::now();
+        let data = data(24);
+        let mut r = Reassembly::default();
+        let first = fragment(1, 0, &data[..16], true);
+        assert!(matches!(r.insert(&first, now), Outcome::Pending));
+        assert!(matches!(r.insert(&first, now), Outcome::Pending));
+        assert_eq!(r.bytes, 16);
+        let Outcome::Complete(datagram) = r.insert(&fragment(1, 16, &data[16..], false), now)
+        else {
+            panic!("not complete");
+        };
+        assert_eq!(payload(&datagram), data);
+    }
+
+    #[test]
+    fn overlap() {
+        let now = Instant::now();
+        let data = data(40);
+        let mut r = Reassembly::default();
+        assert!(matches!(
+            r.insert(&fragment(1, 0, &data[..16], true), now),
+            Outcome::Pending
+        ));
+        assert!(matches!(
+            r.insert(&fragment(1, 8, &data[8..24], true), now),
+            Outcome::Failed
+        ));
+        // the whole datagram went with it
+        assert_eq!(r.next_expiry(), None);
+        assert_eq!(r.bytes, 0);
+    }
+
+    #[test]
+    fn same_offset_other_data() {
+        let now = Instant::now();
+        let mut r = Reassembly::default();
+        assert!(matches!(
+            r.insert(&fragment(1, 0, &[1; 16], true), now),
+            Outcome::Pending
+        ));
+        assert!(matches!(
+            r.insert(&fragment(1, 0, &[2; 16], true), now),
+            Outcome::Failed
+        ));
+    }
+
+    #[test]
+    fn beyond_the_last_fragment() {
+        let now = Instant::now();
+        let mut r = Reassembly::default();
+        assert!(matches!(
+            r.insert(&fragment(1, 16, &[0; 8], false), now),
+            Outcome::Pending
+        ));
+        assert!(matches!(
+            r.insert(&fragment(1, 24, &[0; 8], true), now),
+            Outcome::Failed
+        ));
+    }
+
+    #[test]
+    fn unaligned() {
+        let now = Instant::now();
+        let mut r = Reassembly::default();
+        assert!(matches!(
+            r.insert(&fragment(1, 0, &[0; 12], true), now),
+            Outcome::Failed
+        ));
+        assert_eq!(r.next_expiry(), None);
+    }
+
+    #[test]
+    fn timeout() {
+        let now = Instant::now();
+        let data = data(24);
+        let mut r = Reassembly::default();
+        let first = fragment(1, 0, &data[..16], true);
+        r.insert(&first, now);
+        r.insert(
+            &fragment(2, 8, &data[8..16], true),
+            now + Duration::from_secs(1),
+        );
+        assert_eq!(r.next_expiry(), Some(now + TIMEOUT));
+
+        assert!(r
+            .expire(now + TIMEOUT - Duration::from_millis(1))
+            .is_empty());
+        // the first fragment arrived, so the sender can be told
+        assert_eq!(r.expire(now + TIMEOUT), [Some(first)]);
+        assert_eq!(
+            r.next_expiry(),
+            Some(now + Duration::from_secs(1) + TIMEOUT)
+        );
+        // this one lacks its first fragment
+        assert_eq!(r.expire(now + 2 * TIMEOUT), [None]);
+        assert_eq!(r.bytes, 0);
+
+        // what arrives late starts over
+        assert!(matches!(
+            r.insert(&fragment(1, 16, &data[16..], false), now + 2 * TIMEOUT),
+            Outcome::Pending
+        ));
+    }
+
+    #[test]
+    fn max_datagrams() {
+        let now = Instant::now();
+        let mut r = Reassembly::default();
+        for id in 0..MAX_DATAGRAMS as u16 {
+            assert!(matches!(
+                r.insert(&fragment(id, 0, &[0; 8], true), now),
+                Outcome::Pending
+            ));
+        }
+        let id = MAX_DATAGRAMS as u16;
+        assert!(matches!(
+            r.insert(&fragment(id, 0, &[0; 8], true), now),
+            Outcome::Failed
+        ));
+        // datagrams already being reassembled can still complete
+        assert!(matches!(
+            r.insert(&fragment(0, 8, &[0; 8], false), now),
+            Outcome::Complete(_)
+        ));
+        assert!(matches!(
+            r.insert(&fragment(id, 0, &[0; 8], true), now),
+            Outcome::Pending
+        ));
+    }
+
+    #[test]
+    fn max_bytes() {
+        let now = Instant::now();
+        let mut r = Reassembly::default();
+        let part = [0; 1024];
+        // 32 KiB to each datagram, which holds at most 64 KiB
+        for n in 0..MAX_BYTES / part.len() {
+            let (id, offset) = ((n / 32) as u16, n % 32 * part.len());
+            assert!(matches!(
+                r.insert(&fragment(id, offset, &part, true), now),
+                Outcome::Pending
+            ));
+        }
+        assert!(matches!(
+            r.insert(&fragment(0, 32 * part.len(), &[0; 8], true), now),
+            Outcome::Failed
+        ));
+        // room is made once a datagram times out
+        r.expire(now + TIMEOUT);
+        assert!(matches!(
+            r.insert(&fragment(0, 0, &[0; 8], true), now + TIMEOUT),
+            Outcome::Pending
+        ));
+    }
+}
@@ -0,0 +1,566 @@
+//! ICMP (RFC 792) and ICMPv6 (RFC 4443): echo requests are answered, and errors about TCP
+//! segments we sent are handed to their connection.
+
+use std::{
+    io,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use etherparse::{IpNumber, Ipv4HeaderSlice, Ipv6ExtensionsSlice, Ipv6HeaderSlice};
+
+use crate::{
+    ip::{self, IpHeader, Packet},
+    link::Nic,
+    tcp, udp,
+};
+
+pub(crate) const PROTO_ICMP: u8 = 1;
+pub(crate) const PROTO_ICMPV6: u8 = 58;
+
+const ECHO_REPLY: u8 = 0;
+const DEST_UNREACHABLE: u8 = 3;
+const ECHO_REQUEST: u8 = 8;
+const TIME_EXCEEDED: u8 = 11;
+const DEST_UNREACHABLE_V6: u8 = 1;
+const PACKET_TOO_BIG_V6: u8 = 2;
+const TIME_EXCEEDED_V6: u8 = 3;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+
+/// RFC 1191 S7: likely MTUs, for routers that do not report the next-hop MTU.
+const MTU_PLATEAUS: [usize; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+
+/// RFC 1812 S4.3.2.8, RFC 4443 S2.4 (f): errors we send, at most this many a second.
+const ERROR_RATE: u32 = 100;
+
+/// What became of an inbound ICMP message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// truncated, or with a bad checksum
+    Error,
+    /// an echo request, and whether it has been answered
+    Echo { replied: bool },
+    /// an error about a TCP segment or UDP datagram we sent
+    Report(Report),
+    /// any other message
+    Ignored,
+}
+
+/// An ICMP error about a TCP segment or UDP datagram we sent (RFC 1122 S4.2.3.9, S4.1.3.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Report {
+    /// TCP or UDP
+    pub(crate) protocol: u8,
+    /// the connection or socket, keyed from the point of view of incoming packets
+    pub(crate) quad: tcp::Quad,
+    /// sequence number of the quoted TCP segment
+    pub(crate) seq: u32,
+    /// total length of the quoted datagram
+    pub(crate) len: usize,
+    pub(crate) problem: Problem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Problem {
+    /// the datagram was larger than the `mtu` of the next hop (RFC 1191, RFC 8201)
+    TooBig { mtu: usize },
+    /// the destination cannot be reached; after a hard error the peer will never answer
+    Unreachable { kind: io::ErrorKind, hard: bool },
+}
+
+/// Handle an ICMP or ICMPv6 message, answering echo requests if `echo` is set.
+pub(crate) fn on_packet(nic: &Nic, packet: &Packet, echo: bool, mtu: usize) -> io::Result<Outcome> {
+    let message = packet.payload;
+    // type, code, checksum and the identifier and sequence number of echo messages
+    if message.len() < 8 {
+        return Ok(Outcome::Error);
+    }
+    let (intact, request, reply) = if packet.protocol == PROTO_ICMP {
+        // unlike ICMPv6, ICMP does not cover a pseudo-header
+        let intact = ip::ones_complement_sum(message) == 0xffff;
+        (intact, ECHO_REQUEST, ECHO_REPLY)
+    } else {
+        let intact = ip::transport_checksum_ok(packet);
+        (intact, ECHO_REQUEST_V6, ECHO_REPLY_V6)
+    };
+    if !intact {
+        return Ok(Outcome::Error);
+    }
+    if message[0] != request || message[1] != 0 {
+        return Ok(report(packet).map_or(Outcome::Ignored, Outcome::Report));
+    }
+    if !echo {
+        return Ok(Outcome::Echo { replied: false });
+    }
+
+    let src = packet.header.destination_addr();
+    let dst = packet.header.source_addr();
+    let body = echo_reply(packet, reply);
+    let mut iph = IpHeader::new(src, dst, packet.protocol);
+    if let IpHeader::V4(iph) = &mut iph {
+        // a request that had to be reassembled gets a reply just as large
+        iph.dont_fragment = false;
+    }
+    ip::send(nic, &iph, &body, mtu)?;
+    Ok(Outcome::Echo { replied: true })
+}
+
+/// The `reply` to the echo request in `packet`.
+///
+/// RFC 792, RFC 4443 S4.2: the reply carries the identifier, sequence number and data of the
+/// request, from the address the request was sent to.
+fn echo_reply(packet: &Packet, reply: u8) -> Vec<u8> {
+    let src = packet.header.destination_addr();
+    let dst = packet.header.source_addr();
+    let mut body = packet.payload.to_vec();
+    body[0] = reply;
+    body[2..4].fill(0);
+    let checksum = if packet.protocol == PROTO_ICMP {
+        !ip::ones_complement_sum(&body)
+    } else {
+        ip::transport_checksum(src, dst, packet.protocol, &body)
+    };
+    body[2..4].copy_from_slice(&checksum.to_be_bytes());
+    body
+}
+
+/// A token bucket for the errors we send, so that floods of packets that cause errors do not
+/// cause floods of errors as well.
+#[derive(Debug)]
+pub(crate) struct RateLimit {
+    tokens: u32,
+    refilled: Instant,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            tokens: ERROR_RATE,
+            refilled: Instant::now(),
+        }
+    }
+}
+
+impl RateLimit {
+    /// Whether another error may be sent at `now`.
+    fn allow(&mut self, now: Instant) -> bool {
+        let interval = Duration::from_secs(1) / ERROR_RATE;
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let refill = (elapsed.as_nanos() / interval.as_nanos()).min(ERROR_RATE as u128) as u32;
+        if refill > 0 {
+            self.tokens = (self.tokens + refill).min(ERROR_RATE);
+            self.refilled = now;
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+/// Report that the reassembly of `datagram`, of which only the first fragment arrived, timed
+/// out (RFC 792, RFC 4443 S3.3).
+pub(crate) fn send_reassembly_timeout(
+    nic: &Nic,
+    limit: &mut RateLimit,
+    datagram: &[u8],
+    mtu: usize,
+) -> io::Result<()> {
+    let v4 = (TIME_EXCEEDED, 1);
+    let v6 = (TIME_EXCEEDED_V6, 1);
+    send_error(nic, limit, datagram, v4, v6, mtu)
+}
+
+/// Report that no socket is bound to the UDP port `datagram` was sent to (RFC 1122 S4.1.3.1).
+pub(crate) fn send_port_unreachable(
+    nic: &Nic,
+    limit: &mut RateLimit,
+    datagram: &[u8],
+    mtu: usize,
+) -> io::Result<()> {
+    let v4 = (DEST_UNREACHABLE, 3);
+    let v6 = (DEST_UNREACHABLE_V6, 4);
+    send_error(nic, limit, datagram, v4, v6, mtu)
+}
+
+/// Send an ICMP error of type and code `v4` or `v6`, whichever matches the family of
+/// `datagram`, back to the source of `datagram`.
+fn send_error(
+    nic: &Nic,
+    limit: &mut RateLimit,
+    datagram: &[u8],
+    v4: (u8, u8),
+    v6: (u8, u8),
+    mtu: usize,
+) -> io::Result<()> {
+    let Some((src, dst, protocol, payload)) = offending(datagram) else {
+        return Ok(());
+    };
+    // RFC 1122 S3.2.2, RFC 4443 S2.4: never an error about an error, which are the ICMPv6
+    // types below 128 and all ICMP types but the echo and information requests
+    let about_error = match (protocol, payload.first()) {
+        (PROTO_ICMP, Some(&t)) => !matches!(t, ECHO_REQUEST | 13 | 15 | 17),
+        (PROTO_ICMPV6, Some(&t)) => t < 128,
+        _ => false,
+    };
+    if about_error || !limit.allow(Instant::now()) {
+        return Ok(());
+    }
+    let ((kind, code), protocol, quoted) = if src.is_ipv4() {
+        // RFC 792: the header and the first 64 bits of the data
+        (v4, PROTO_ICMP, datagram.len() - payload.len() + 8)
+    } else {
+        // RFC 4443 S2.4: as much as fits into the minimum MTU
+        (v6, PROTO_ICMPV6, 1280 - 40 - 8)
+    };
+    let quoted = &datagram[..quoted.min(datagram.len())];
+
+    // answered from the address the datagram was sent to
+    let mut body = vec![kind, code, 0, 0, 0, 0, 0, 0];
+    body.extend_from_slice(quoted);
+    let checksum = if protocol == PROTO_ICMP {
+        !ip::ones_complement_sum(&body)
+    } else {
+        ip::transport_checksum(dst, src, protocol, &body)
+    };
+    body[2..4].copy_from_slice(&checksum.to_be_bytes());
+    ip::send(nic, &IpHeader::new(dst, src, protocol), &body, mtu)
+}
+
+/// Source, destination, protocol and payload of a datagram we received, which may be a
+/// fragment.
+fn offending(datagram: &[u8]) -> Option<(IpAddr, IpAddr, u8, &[u8])> {
+    match datagram.first().map(|b| b >> 4) {
+        Some(4) => {
+            let iph = Ipv4HeaderSlice::from_slice(datagram).ok()?;
+            Some((
+                iph.source_addr().into(),
+                iph.destination_addr().into(),
+                iph.protocol(),
+                &datagram[iph.slice().len()..],
+            ))
+        }
+        Some(6) => {
+            let iph = Ipv6HeaderSlice::from_slice(datagram).ok()?;
+            let rest = &datagram[iph.slice().len()..];
+            let (_, protocol, payload) =
+                Ipv6ExtensionsSlice::from_slice(iph.next_header(), rest).ok()?;
+            Some((
+                iph.source_addr().into(),
+                iph.destination_addr().into(),
+                protocol,
+                payload,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// The report of an ICMP error about a TCP segment or UDP datagram, if `packet` is one.
+fn report(packet: &Packet) -> Option<Report> {
+    let message = packet.payload;
+    let (src, dst, protocol, len, segment) = quoted(packet)?;
+    // only we could have sent the datagram
+    if src != packet.header.destination_addr() {
+        return None;
+    }
+    if protocol != IpNumber::Tcp as u8 && protocol != udp::PROTO_UDP {
+        return None;
+    }
+    // RFC 792, RFC 4443: at least the ports, and the TCP sequence number, are quoted
+    if segment.len() < 8 {
+        return None;
+    }
+    let (kind, hard) = match (packet.protocol, message[0], message[1]) {
+        (PROTO_ICMP, DEST_UNREACHABLE, 4) => {
+            let mtu = u16::from_be_bytes([message[6], message[7]]) as usize;
+            let mtu = if mtu == 0 {
+                // RFC 1191 S5: an old router, so guess from the size of the datagram
+                MTU_PLATEAUS.into_iter().find(|&p| p < len).unwrap_or(68)
+            } else {
+                mtu
+            };
+            return Some(Report::new(
+                src,
+                dst,
+                protocol,
+                segment,
+                len,
+                Problem::TooBig { mtu },
+            ));
+        }
+        (PROTO_ICMPV6, PACKET_TOO_BIG_V6, _) => {
+            let mtu = u32::from_be_bytes([message[4], message[5], message[6], message[7]]);
+            let mtu = mtu as usize;
+            return Some(Report::new(
+                src,
+                dst,
+                protocol,
+                segment,
+                len,
+                Problem::TooBig { mtu },
+            ));
+        }
+        // RFC 1122 S4.2.3.9: protocol and port unreachable are hard errors
+        (PROTO_ICMP, DEST_UNREACHABLE, 2 | 3) | (PROTO_ICMPV6, DEST_UNREACHABLE_V6, 4) => {
+            (io::ErrorKind::ConnectionRefused, true)
+        }
+        (PROTO_ICMP, DEST_UNREACHABLE, 0 | 6) | (PROTO_ICMPV6, DEST_UNREACHABLE_V6, 0) => {
+            (io::ErrorKind::NetworkUnreachable, false)
+        }
+        (PROTO_ICMP, DEST_UNREACHABLE | TIME_EXCEEDED, _)
+        | (PROTO_ICMPV6, DEST_UNREACHABLE_V6 | TIME_EXCEEDED_V6, _) => {
+            (io::ErrorKind::HostUnreachable, false)
+        }
+        _ => return None,
+    };
+    Some(Report::new(
+        src,
+        dst,
+        protocol,
+        segment,
+        len,
+        Problem::Unreachable { kind, hard },
+    ))
+}
+
+/// Source, destination, protocol and total length of the datagram quoted by an ICMP error,
+/// and what is quoted of its payload.
+fn quoted<'a>(packet: &Packet<'a>) -> Option<(IpAddr, IpAddr, u8, usize, &'a [u8])> {
+    let quoted = &packet.payload[8..];
+    if packet.protocol == PROTO_ICMP {
+        let iph = Ipv4HeaderSlice::from_slice(quoted).ok()?;
+        let segment = &quoted[iph.slice().len()..];
+        Some((
+            iph.source_addr().into(),
+            iph.destination_addr().into(),
+            iph.protocol(),
+            iph.total_len() as usize,
+            segment,
+        ))
+    } else {
+        let iph = Ipv6HeaderSlice::from_slice(quoted).ok()?;
+        let header_len = iph.slice().len();
+        let (_, protocol, segment) =
+            Ipv6ExtensionsSlice::from_slice(iph.next_header(), &quoted[header_len..]).ok()?;
+        Some((
+            iph.source_addr().into(),
+            iph.destination_addr().into(),
+            protocol,
+            header_len + iph.payload_length() as usize,
+            segment,
+        ))
+    }
+}
+
+impl Report {
+    fn new(
+        src: IpAddr,
+        dst: IpAddr,
+        protocol: u8,
+        segment: &[u8],
+        len: usize,
+        problem: Problem,
+    ) -> Self {
+        let port = |at: usize| u16::from_be_bytes([segment[at], segment[at + 1]]);
+        Report {
+            protocol,
+            quad: tcp::Quad {
+                src: (dst, port(2)),
+                dst: (src, port(0)),
+            },
+            seq: u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]),
+            len,
+            problem,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use etherparse::PacketBuilder;
+
+    use super::*;
+
+    const US: [u8; 4] = [192, 168, 0, 2];
+    const PEER: [u8; 4] = [192, 168, 0, 1];
+    const ROUTER: [u8; 4] = [192, 168, 0, 254];
+    const US6: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+    const PEER6: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    const ROUTER6: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xfe];
+
+    fn local() -> Vec<IpAddr> {
+        vec![Ipv4Addr::from(US).into(), Ipv6Addr::from(US6).into()]
+    }
+
+    /// A segment of 1000 bytes with sequence number 1234 that we sent from port 40000 to 80.
+    fn segment_v4(src: [u8; 4]) -> Vec<u8> {
+        let mut segment = Vec::new();
+        PacketBuilder::ipv4(src, PEER, 64)
+            .tcp(40000, 80, 1234, 1000)
+            .write(&mut segment, &[0; 1000])
+            .unwrap();
+        segment
+    }
+
+    fn segment_v6() -> Vec<u8> {
+        let mut segment = Vec::new();
+        PacketBuilder::ipv6(US6, PEER6, 64)
+            .tcp(40000, 80, 1234, 1000)
+            .write(&mut segment, &[0; 1000])
+            .unwrap();
+        segment
+    }
+
+    /// The report of an ICMP error of `kind` and `code` from a router, quoting `quoted`.
+    fn report_v4(kind: u8, code: u8, rest: [u8; 4], quoted: &[u8]) -> Option<Report> {
+        let mut message = Vec::new();
+        PacketBuilder::ipv4(ROUTER, US, 64)
+            .icmpv4_raw(kind, code, rest)
+            .write(&mut message, quoted)
+            .unwrap();
+        report(&ip::parse(&message, &local()).unwrap())
+    }
+
+    fn report_v6(kind: u8, code: u8, rest: [u8; 4], quoted: &[u8]) -> Option<Report> {
+        let mut message = Vec::new();
+        PacketBuilder::ipv6(ROUTER6, US6, 64)
+            .icmpv6_raw(kind, code, rest)
+            .write(&mut message, quoted)
+            .unwrap();
+        report(&ip::parse(&message, &local()).unwrap())
+    }
+
+    #[test]
+    fn quoted_segment() {
+        // RFC 792: the IP header and the first 8 bytes of the segment are enough
+        let segment = segment_v4(US);
+        let report = report_v4(DEST_UNREACHABLE, 1, [0; 4], &segment[..28]).unwrap();
+        assert_eq!(report.protocol, 6);
+        let us: IpAddr = Ipv4Addr::from(US).into();
+        let peer: IpAddr = Ipv4Addr::from(PEER).into();
+        assert_eq!(report.quad.src, (peer, 80));
+        assert_eq!(report.quad.dst, (us, 40000));
+        assert_eq!(report.seq, 1234);
+        assert_eq!(report.len, 1040);
+        assert_eq!(
+            report.problem,
+            Problem::Unreachable {
+                kind: io::ErrorKind::HostUnreachable,
+                hard: false
+            }
+        );
+
+        // but not less
+        assert_eq!(report_v4(DEST_UNREACHABLE, 1, [0; 4], &segment[..27]), None);
+        // nor about a datagram someone else sent
+        let forged = segment_v4(ROUTER);
+        assert_eq!(report_v4(DEST_UNREACHABLE, 1, [0; 4], &forged[..28]), None);
+    }
+
+    #[test]
+    fn unreachable() {
+        let segment = segment_v4(US);
+        let problem = |code| {
+            report_v4(DEST_UNREACHABLE, code, [0; 4], &segment)
+                .unwrap()
+                .problem
+        };
+        // RFC 1122 S4.2.3.9: protocol and port unreachable are hard errors
+        let refused = Problem::Unreachable {
+            kind: io::ErrorKind::ConnectionRefused,
+            hard: true,
+        };
+        assert_eq!(problem(2), refused);
+        assert_eq!(problem(3), refused);
+        let network = Problem::Unreachable {
+            kind: io::ErrorKind::NetworkUnreachable,
+            hard: false,
+        };
+        assert_eq!(problem(0), network);
+        assert_eq!(problem(6), network);
+        let expired = report_v4(TIME_EXCEEDED, 0, [0; 4], &segment).unwrap();
+        assert_eq!(
+            expired.problem,
+            Problem::Unreachable {
+                kind: io::ErrorKind::HostUnreachable,
+                hard: false
+            }
+        );
+        // redirects and the like are no errors about the segment
+        assert_eq!(report_v4(5, 1, [0; 4], &segment), None);
+
+        let segment = segment_v6();
+        let port = report_v6(DEST_UNREACHABLE_V6, 4, [0; 4], &segment).unwrap();
+        assert_eq!(port.problem, refused);
+        assert_eq!(port.seq, 1234);
+        assert_eq!(port.len, 1060);
+    }
+
+    #[test]
+    fn too_big() {
+        let segment = segment_v4(US);
+        let mtu = report_v4(DEST_UNREACHABLE, 4, [0, 0, 0x05, 0x78], &segment).unwrap();
+        assert_eq!(mtu.problem, Problem::TooBig { mtu: 1400 });
+        // RFC 1191 S5: a router that does not report the MTU gets the next lower plateau
+        let old = report_v4(DEST_UNREACHABLE, 4, [0; 4], &segment).unwrap();
+        assert_eq!(old.problem, Problem::TooBig { mtu: 1006 });
+
+        let segment = segment_v6();
+        let mtu = report_v6(PACKET_TOO_BIG_V6, 0, 1280u32.to_be_bytes(), &segment).unwrap();
+        assert_eq!(mtu.problem, Problem::TooBig { mtu: 1280 });
+    }
+
+    #[test]
+    fn echo() {
+        let mut request = Vec::new();
+        PacketBuilder::ipv4(PEER, US, 64)
+            .icmpv4_echo_request(0x1234, 7)
+            .write(&mut request, b"ping")
+            .unwrap();
+        let request = ip::parse(&request, &local()).unwrap();
+        let reply = echo_reply(&request, ECHO_REPLY);
+        assert_eq!(reply[..2], [ECHO_REPLY, 0]);
+        // identifier, sequence number and data
+        assert_eq!(reply[4..], request.payload[4..]);
+        assert_eq!(ip::ones_complement_sum(&reply), 0xffff);
+
+        let mut request = Vec::new();
+        PacketBuilder::ipv6(PEER6, US6, 64)
+            .icmpv6_echo_request(0x1234, 7)
+            .write(&mut request, b"ping")
+            .unwrap();
+        let request = ip::parse(&request, &local()).unwrap();
+        let reply = echo_reply(&request, ECHO_REPLY_V6);
+        assert_eq!(reply[..2], [ECHO_REPLY_V6, 0]);
+        assert_eq!(reply[4..], request.payload[4..]);
+        // the pseudo-header has the addresses the other way around
+        let mut unsummed = reply.clone();
+        unsummed[2..4].fill(0);
+        let checksum = ip::transport_checksum(US6.into(), PEER6.into(), PROTO_ICMPV6, &unsummed);
+        assert_eq!(reply[2..4], checksum.to_be_bytes());
+    }
+
+    #[test]
+    fn only_tcp_and_udp() {
+        let mut ping = Vec::new();
+        PacketBuilder::ipv4(US, PEER, 64)
+            .icmpv4_echo_request(1, 1)
+            .write(&mut ping, &[0; 8])
+            .unwrap();
+        assert_eq!(report_v4(DEST_UNREACHABLE, 1, [0; 4], &ping), None);
+
+        let mut udp = Vec::new();
+        PacketBuilder::ipv4(US, PEER, 64)
+            .udp(5353, 53)
+            .write(&mut udp, &[0; 8])
+            .unwrap();
+        let report = report_v4(DEST_UNREACHABLE, 3, [0; 4], &udp).unwrap();
+        assert_eq!(report.protocol, udp::PROTO_UDP);
+        assert_eq!(report.quad.dst.1, 5353);
+    }
+}
@@ -0,0 +1,537 @@
+//! IPv4 and IPv6 headers, and the checks applied to every packet before it reaches TCP.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use etherparse::{
+    Ipv4Header, Ipv4HeaderSlice, Ipv6ExtensionsSlice, Ipv6Header, Ipv6HeaderSlice, TcpHeader,
+    ValueError, WriteError,
+};
+
+use crate::link::Nic;
+
+/// Identification of the next datagram that may be fragmented (RFC 6864 S4.1).
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+/// Why an inbound packet was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Discard {
+    /// malformed header, bad header checksum or zero TTL
+    Header,
+    /// not addressed to the interface
+    Address,
+    /// part of a fragmented datagram, which is for the caller to reassemble
+    Fragment,
+}
+
+/// The ECN field of an IP header (RFC 3168 S5).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EcnCodepoint {
+    /// not ECN-capable
+    NotEct = 0b00,
+    Ect1 = 0b01,
+    Ect0 = 0b10,
+    /// congestion experienced, set by a router instead of dropping the packet
+    Ce = 0b11,
+}
+
+impl EcnCodepoint {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => EcnCodepoint::NotEct,
+            0b01 => EcnCodepoint::Ect1,
+            0b10 => EcnCodepoint::Ect0,
+            _ => EcnCodepoint::Ce,
+        }
+    }
+}
+
+/// The header of an inbound packet.
+#[derive(Debug, Clone)]
+pub(crate) enum IpHeaderSlice<'a> {
+    V4(Ipv4HeaderSlice<'a>),
+    V6(Ipv6HeaderSlice<'a>),
+}
+
+impl IpHeaderSlice<'_> {
+    pub(crate) fn source_addr(&self) -> IpAddr {
+        match self {
+            IpHeaderSlice::V4(iph) => iph.source_addr().into(),
+            IpHeaderSlice::V6(iph) => iph.source_addr().into(),
+        }
+    }
+
+    pub(crate) fn destination_addr(&self) -> IpAddr {
+        match self {
+            IpHeaderSlice::V4(iph) => iph.destination_addr().into(),
+            IpHeaderSlice::V6(iph) => iph.destination_addr().into(),
+        }
+    }
+
+    pub(crate) fn ecn(&self) -> EcnCodepoint {
+        match self {
+            IpHeaderSlice::V4(iph) => EcnCodepoint::from_bits(iph.ecn()),
+            // the low two bits of the traffic class (RFC 3168 S5)
+            IpHeaderSlice::V6(iph) => EcnCodepoint::from_bits(iph.traffic_class()),
+        }
+    }
+}
+
+/// A validated inbound packet.
+#[derive(Debug, Clone)]
+pub(crate) struct Packet<'a> {
+    pub(crate) header: IpHeaderSlice<'a>,
+    /// the transport protocol, after any IPv6 extension headers
+    pub(crate) protocol: u8,
+    pub(crate) payload: &'a [u8],
+}
+
+/// The header of outbound packets of one connection.
+#[derive(Debug, Clone)]
+pub(crate) enum IpHeader {
+    V4(Ipv4Header),
+    V6(Ipv6Header),
+}
+
+impl IpHeader {
+    /// A header from `src` to `dst`, which must be of the same family.
+    pub(crate) fn new(src: IpAddr, dst: IpAddr, protocol: u8) -> Self {
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                IpHeader::V4(Ipv4Header::new(0, 64, protocol, src.octets(), dst.octets()))
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => IpHeader::V6(Ipv6Header {
+                traffic_class: 0,
+                flow_label: 0,
+                payload_length: 0,
+                next_header: protocol,
+                hop_limit: 64,
+                source: src.octets(),
+                destination: dst.octets(),
+            }),
+            _ => panic!("addresses of different families: {src} and {dst}"),
+        }
+    }
+
+    pub(crate) fn header_len(&self) -> usize {
+        match self {
+            IpHeader::V4(iph) => iph.header_len(),
+            IpHeader::V6(iph) => iph.header_len(),
+        }
+    }
+
+    pub(crate) fn set_ecn(&mut self, ecn: EcnCodepoint) {
+        match self {
+            IpHeader::V4(iph) => iph.explicit_congestion_notification = ecn as u8,
+            IpHeader::V6(iph) => iph.traffic_class = (iph.traffic_class & !0b11) | ecn as u8,
+        }
+    }
+
+    pub(crate) fn set_payload_len(&mut self, len: usize) -> Result<(), ValueError> {
+        match self {
+            IpHeader::V4(iph) => iph.set_payload_len(len),
+            IpHeader::V6(iph) => iph.set_payload_length(len),
+        }
+    }
+
+    /// The checksum of a TCP segment with `tcp` and `payload` sent under this header.
+    pub(crate) fn tcp_checksum(&self, tcp: &TcpHeader, payload: &[u8]) -> Result<u16, ValueError> {
+        match self {
+            IpHeader::V4(iph) => tcp.calc_checksum_ipv4(iph, payload),
+            IpHeader::V6(iph) => tcp.calc_checksum_ipv6(iph, payload),
+        }
+    }
+
+    pub(crate) fn write<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        let written = match self {
+            IpHeader::V4(iph) => iph.write(w),
+            IpHeader::V6(iph) => iph.write(w),
+        };
+        written.map_err(|e| match e {
+            WriteError::IoError(e) => e,
+            WriteError::SliceTooSmall(len) => io::Error::new(
+                io::ErrorKind::Interrupted,
+                format!("slice too small with length: {len}"),
+            ),
+            WriteError::ValueError(v) => io::Error::other(v.to_string()),
+        })
+    }
+}
+
+fn too_big() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "datagram larger than the MTU")
+}
+
+/// Send `payload` under `header`, which gets the payload length filled in.
+///
+/// IPv4 datagrams larger than `mtu` are fragmented (RFC 791 S3.2) unless their DF flag is set;
+/// other datagrams that do not fit are rejected.
+pub(crate) fn send(nic: &Nic, header: &IpHeader, payload: &[u8], mtu: usize) -> io::Result<()> {
+    let mut header = header.clone();
+    if header.header_len() + payload.len() <= mtu {
+        header
+            .set_payload_len(payload.len())
+            .map_err(|_| too_big())?;
+        let mut packet = Vec::with_capacity(header.header_len() + payload.len());
+        header.write(&mut packet)?;
+        packet.extend_from_slice(payload);
+        nic.send(&packet)?;
+        return Ok(());
+    }
+
+    let IpHeader::V4(iph) = &mut header else {
+        return Err(too_big());
+    };
+    // we never send options, which would have to be sorted into those copied to every fragment
+    if iph.dont_fragment || payload.len() > u16::MAX as usize || iph.header_len() > 20 {
+        return Err(too_big());
+    }
+    // offsets count in units of 8 bytes, so all but the last fragment carry a multiple of that
+    let chunk = mtu.saturating_sub(iph.header_len()) / 8 * 8;
+    if chunk == 0 {
+        return Err(too_big());
+    }
+    iph.identification = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    for (i, part) in payload.chunks(chunk).enumerate() {
+        let offset = i * chunk;
+        iph.fragments_offset = (offset / 8) as u16;
+        iph.more_fragments = offset + part.len() < payload.len();
+        iph.set_payload_len(part.len()).map_err(|_| too_big())?;
+        let mut packet = Vec::with_capacity(iph.header_len() + part.len());
+        IpHeader::V4(iph.clone()).write(&mut packet)?;
+        packet.extend_from_slice(part);
+        nic.send(&packet)?;
+    }
+    Ok(())
+}
+
+/// The Internet checksum (RFC 1071) of `data`, folded but not complemented.
+pub(crate) fn ones_complement_sum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// One's complement addition of two folded sums.
+fn add_sums(a: u16, b: u16) -> u16 {
+    let sum = a as u32 + b as u32;
+    ((sum & 0xffff) + (sum >> 16)) as u16
+}
+
+/// The folded sum of the pseudo-header (RFC 793 S3.1, RFC 8200 S8.1) covering `len` bytes of
+/// `protocol` from `src` to `dst`.
+fn pseudo_header_sum(src: IpAddr, dst: IpAddr, protocol: u8, len: usize) -> u16 {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut pseudo = [0u8; 12];
+            pseudo[..4].copy_from_slice(&src.octets());
+            pseudo[4..8].copy_from_slice(&dst.octets());
+            pseudo[9] = protocol;
+            pseudo[10..].copy_from_slice(&(len as u16).to_be_bytes());
+            ones_complement_sum(&pseudo)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut pseudo = [0u8; 40];
+            pseudo[..16].copy_from_slice(&src.octets());
+            pseudo[16..32].copy_from_slice(&dst.octets());
+            pseudo[32..36].copy_from_slice(&(len as u32).to_be_bytes());
+            pseudo[39] = protocol;
+            ones_complement_sum(&pseudo)
+        }
+        _ => panic!("addresses of different families: {src} and {dst}"),
+    }
+}
+
+/// Whether the checksum of the TCP, UDP or ICMPv6 message in `packet` is intact.
+///
+/// The checksum covers the pseudo-header as well as the whole message.
+pub(crate) fn transport_checksum_ok(packet: &Packet) -> bool {
+    let pseudo = pseudo_header_sum(
+        packet.header.source_addr(),
+        packet.header.destination_addr(),
+        packet.protocol,
+        packet.payload.len(),
+    );
+    // the checksum field is included, so an intact message sums to all ones
+    add_sums(pseudo, ones_complement_sum(packet.payload)) == 0xffff
+}
+
+/// The checksum of a TCP, UDP or ICMPv6 `message` from `src` to `dst`, computed with its
+/// checksum field set to zero.
+pub(crate) fn transport_checksum(src: IpAddr, dst: IpAddr, protocol: u8, message: &[u8]) -> u16 {
+    let pseudo = pseudo_header_sum(src, dst, protocol, message.len());
+    !add_sums(pseudo, ones_complement_sum(message))
+}
+
+/// Whether `addr` is one of the `local` addresses, or with none configured, any unicast address.
+pub(crate) fn is_local(local: &[IpAddr], addr: IpAddr) -> bool {
+    if !local.is_empty() {
+        return local.contains(&addr);
+    }
+    match addr {
+        IpAddr::V4(addr) => !addr.is_broadcast() && !addr.is_multicast() && !addr.is_unspecified(),
+        IpAddr::V6(addr) => !addr.is_multicast() && !addr.is_unspecified(),
+    }
+}
+
+/// Parse and validate an inbound IPv4 or IPv6 packet.
+///
+/// Trailing bytes beyond the length given in the header are not part of the payload.
+pub(crate) fn parse<'a>(packet: &'a [u8], local: &[IpAddr]) -> Result<Packet<'a>, Discard> {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => parse_v4(packet, local),
+        Some(6) => parse_v6(packet, local),
+        _ => Err(Discard::Header),
+    }
+}
+
+fn parse_v4<'a>(packet: &'a [u8], local: &[IpAddr]) -> Result<Packet<'a>, Discard> {
+    let iph = Ipv4HeaderSlice::from_slice(packet).map_err(|_| Discard::Header)?;
+    let header_len = iph.slice().len();
+    let total_len = iph.total_len() as usize;
+    if total_len < header_len || total_len > packet.len() {
+        return Err(Discard::Header);
+    }
+    // the checksum field is included, so an intact header sums to all ones
+    if ones_complement_sum(iph.slice()) != 0xffff {
+        return Err(Discard::Header);
+    }
+    // RFC 1122 S3.2.1.7: a host must never send a datagram with a TTL of zero
+    if iph.ttl() == 0 {
+        return Err(Discard::Header);
+    }
+    if !is_local(local, iph.destination_addr().into()) {
+        return Err(Discard::Address);
+    }
+    let src: Ipv4Addr = iph.source_addr();
+    if src.is_broadcast() || src.is_multicast() {
+        // RFC 1122 S3.2.1.3: nobody could be answered there
+        return Err(Discard::Address);
+    }
+    if iph.more_fragments() || iph.fragments_offset() != 0 {
+        return Err(Discard::Fragment);
+    }
+    Ok(Packet {
+        protocol: iph.protocol(),
+        header: IpHeaderSlice::V4(iph),
+        payload: &packet[header_len..total_len],
+    })
+}
+
+fn parse_v6<'a>(packet: &'a [u8], local: &[IpAddr]) -> Result<Packet<'a>, Discard> {
+    let iph = Ipv6HeaderSlice::from_slice(packet).map_err(|_| Discard::Header)?;
+    let header_len = iph.slice().len();
+    // a zero payload length announces a jumbogram (RFC 2675), which cannot fit our MTU anyway
+    let total_len = header_len + iph.payload_length() as usize;
+    if iph.payload_length() == 0 || total_len > packet.len() {
+        return Err(Discard::Header);
+    }
+    if !is_local(local, iph.destination_addr().into()) {
+        return Err(Discard::Address);
+    }
+    let src: Ipv6Addr = iph.source_addr();
+    if src.is_multicast() {
+        return Err(Discard::Address);
+    }
+    let (extensions, protocol, payload) =
+        Ipv6ExtensionsSlice::from_slice(iph.next_header(), &packet[header_len..total_len])
+            .map_err(|_| Discard::Header)?;
+    if extensions.is_fragmenting_payload() {
+        // TODO: reassembly, which is only done for IPv4 so far
+        return Err(Discard::Fragment);
+    }
+    Ok(Packet {
+        header: IpHeaderSlice::V6(iph),
+        protocol,
+        payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Stats;
+    use etherparse::PacketBuilder;
+
+    const US: [u8; 4] = [192, 168, 0, 2];
+    const PEER: [u8; 4] = [192, 168, 0, 1];
+    const US6: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+    const PEER6: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+    fn local() -> Vec<IpAddr> {
+        vec![Ipv4Addr::from(US).into(), Ipv6Addr::from(US6).into()]
+    }
+
+    fn tcp_v4(src: [u8; 4], dst: [u8; 4]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4(src, dst, 64)
+            .tcp(1, 2, 0, 1000)
+            .write(&mut packet, b"hello")
+            .unwrap();
+        packet
+    }
+
+    fn tcp_v6(src: [u8; 16], dst: [u8; 16]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv6(src, dst, 64)
+            .tcp(1, 2, 0, 1000)
+            .write(&mut packet, b"hello")
+            .unwrap();
+        packet
+    }
+
+    /// Recompute the header checksum after a field was changed, so that only the field is bad.
+    fn reseal(packet: &mut [u8]) {
+        let len = (packet[0] & 0xf) as usize * 4;
+        packet[10..12].fill(0);
+        let sum = !ones_complement_sum(&packet[..len.clamp(20, packet.len())]);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    /// The drop counters after `packets` arrived.
+    fn count(packets: &[Vec<u8>]) -> Stats {
+        let mut stats = Stats::default();
+        for packet in packets {
+            if let Err(discard) = parse(packet, &local()) {
+                stats.discarded(discard);
+            }
+        }
+        stats
+    }
+
+    #[test]
+    fn valid() {
+        let mut packet = tcp_v4(PEER, US);
+        // padding of the link layer
+        packet.extend_from_slice(&[0; 6]);
+        let parsed = parse(&packet, &local()).unwrap();
+        assert_eq!(parsed.protocol, 6);
+        assert_eq!(parsed.payload.len(), 20 + 5);
+        assert!(transport_checksum_ok(&parsed));
+
+        let packet = tcp_v6(PEER6, US6);
+        let parsed = parse(&packet, &local()).unwrap();
+        assert_eq!(parsed.protocol, 6);
+        assert_eq!(parsed.payload.len(), 20 + 5);
+        assert!(transport_checksum_ok(&parsed));
+    }
+
+    #[test]
+    fn bad_headers() {
+        let mut bad = Vec::new();
+
+        let mut version = tcp_v4(PEER, US);
+        version[0] = 0x55;
+        reseal(&mut version);
+        bad.push(version);
+
+        for ihl in [4, 15] {
+            let mut packet = tcp_v4(PEER, US);
+            packet[0] = 0x40 | ihl;
+            reseal(&mut packet);
+            bad.push(packet);
+        }
+
+        for total_len in [19u16, 100] {
+            let mut packet = tcp_v4(PEER, US);
+            packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+            reseal(&mut packet);
+            bad.push(packet);
+        }
+
+        let mut checksum = tcp_v4(PEER, US);
+        checksum[10] ^= 0xff;
+        bad.push(checksum);
+
+        let mut ttl = tcp_v4(PEER, US);
+        ttl[8] = 0;
+        reseal(&mut ttl);
+        bad.push(ttl);
+
+        let mut truncated = tcp_v6(PEER6, US6);
+        truncated.truncate(50);
+        bad.push(truncated);
+
+        for packet in &bad {
+            assert_eq!(parse(packet, &local()).unwrap_err(), Discard::Header);
+        }
+        let stats = count(&bad);
+        assert_eq!(stats.in_hdr_errors, bad.len() as u64);
+        assert_eq!(stats.in_addr_errors, 0);
+    }
+
+    #[test]
+    fn foreign_addresses() {
+        let bad = [
+            tcp_v4(PEER, [192, 168, 0, 3]),
+            tcp_v4([255, 255, 255, 255], US),
+            tcp_v4([224, 0, 0, 1], US),
+            tcp_v6(PEER6, PEER6),
+        ];
+        for packet in &bad {
+            assert_eq!(parse(packet, &local()).unwrap_err(), Discard::Address);
+        }
+        let stats = count(&bad);
+        assert_eq!(stats.in_addr_errors, bad.len() as u64);
+        assert_eq!(stats.in_hdr_errors, 0);
+
+        // without configured addresses, any unicast destination is ours
+        assert!(parse(&tcp_v4(PEER, [10, 0, 0, 1]), &[]).is_ok());
+        let broadcast = tcp_v4(PEER, [255, 255, 255, 255]);
+        assert_eq!(parse(&broadcast, &[]).unwrap_err(), Discard::Address);
+    }
+
+    #[test]
+    fn fragments() {
+        let mut packet = tcp_v4(PEER, US);
+        // more fragments
+        packet[6] = 0x20;
+        reseal(&mut packet);
+        assert_eq!(parse(&packet, &local()).unwrap_err(), Discard::Fragment);
+        assert_eq!(count(&[packet]).in_frag_drops, 1);
+    }
+
+    #[test]
+    fn corrupted_transport() {
+        let v4 = tcp_v4(PEER, US);
+        let v6 = tcp_v6(PEER6, US6);
+        for packet in [v4, v6] {
+            let header_len = packet.len() - 25;
+            // in the TCP header, in the payload, and in the checksum itself
+            for i in [header_len + 4, packet.len() - 1, header_len + 16] {
+                let mut corrupted = packet.clone();
+                corrupted[i] ^= 0x01;
+                assert!(!transport_checksum_ok(
+                    &parse(&corrupted, &local()).unwrap()
+                ));
+            }
+        }
+
+        let mut udp = Vec::new();
+        PacketBuilder::ipv4(PEER, US, 64)
+            .udp(1, 2)
+            .write(&mut udp, b"hello")
+            .unwrap();
+        assert!(transport_checksum_ok(&parse(&udp, &local()).unwrap()));
+        *udp.last_mut().unwrap() ^= 0x01;
+        assert!(!transport_checksum_ok(&parse(&udp, &local()).unwrap()));
+    }
+
+    #[test]
+    fn checksum_roundtrip() {
+        let packet = tcp_v4(PEER, US);
+        let parsed = parse(&packet, &local()).unwrap();
+        let mut message = parsed.payload.to_vec();
+        message[16..18].fill(0);
+        let sum = transport_checksum(PEER.into(), US.into(), 6, &message);
+        assert_eq!(sum.to_be_bytes(), parsed.payload[16..18]);
+    }
+}
@@ -0,0 +1,210 @@
+//! LEDBAT (RFC 6817): a scavenger that keeps the queuing delay it adds below a target, and so
+//! yields to any loss-based flow sharing the bottleneck.
+//!
+//! The delays are taken from round-trip times, since segments carry no timestamps: the
+//! queuing delay is how far the recent round-trip times are above the lowest one.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// RFC 6817 S2.4.2: the queuing delay LEDBAT aims to add.
+const TARGET: Duration = Duration::from_millis(100);
+
+/// RFC 6817 S2.4.2: how fast the window reacts to the delay being off target.
+const GAIN: f64 = 1.0;
+
+/// RFC 6817 S2.4.2: segments the window may be ahead of what is in flight.
+const ALLOWED_INCREASE: u32 = 1;
+
+/// RFC 6817 S2.4.2: the window never goes below this many segments.
+const MIN_CWND: u32 = 2;
+
+/// RFC 6817 S3.4.2: RTT samples whose minimum is the current delay, so that single outliers
+/// do not count.
+const CURRENT_FILTER: usize = 4;
+
+/// RFC 6817 S3.4.2: minutes the lowest delay of each is remembered for, so that the base delay
+/// follows route changes.
+const BASE_HISTORY: usize = 10;
+
+const BASE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The state of LEDBAT for one connection; windows are in bytes.
+#[derive(Debug, Default)]
+pub(crate) struct Ledbat {
+    current: VecDeque<Duration>,
+    /// the lowest delay of each of the last `BASE_HISTORY` minutes, and when the last began
+    base: VecDeque<Duration>,
+    base_since: Option<Instant>,
+    /// growth of less than a byte, carried over to the next ACK
+    carry: f64,
+}
+
+impl Ledbat {
+    /// A round-trip time measured at `now` from an ACK.
+    pub(crate) fn on_rtt(&mut self, rtt: Duration, now: Instant) {
+        if self.current.len() == CURRENT_FILTER {
+            self.current.pop_front();
+        }
+        self.current.push_back(rtt);
+
+        match self.base_since {
+            Some(since) if now - since < BASE_INTERVAL => {
+                let last = self.base.back_mut().expect("a minute has begun");
+                *last = (*last).min(rtt);
+            }
+            _ => {
+                if self.base.len() == BASE_HISTORY {
+                    self.base.pop_front();
+                }
+                self.base.push_back(rtt);
+                self.base_since = Some(now);
+            }
+        }
+    }
+
+    /// How much the path's queues currently add to the round-trip time.
+    pub(crate) fn queuing_delay(&self) -> Duration {
+        let current = self.current.iter().min();
+        let base = self.base.iter().min();
+        match (current, base) {
+            (Some(current), Some(base)) => current.saturating_sub(*base),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Whether the initial slow start should end: only at half the target, so that the
+    /// delay-based control starts out with room to grow.
+    pub(crate) fn leave_slow_start(&self) -> bool {
+        self.queuing_delay() > TARGET / 2
+    }
+
+    /// The window after `acked` bytes were acked in congestion avoidance with a window of
+    /// `cwnd` and `flight` bytes in flight (RFC 6817 S2.4.2).
+    pub(crate) fn on_ack(&mut self, cwnd: u32, acked: u32, flight: u32, mss: u32) -> u32 {
+        let delay = self.queuing_delay().as_secs_f64();
+        let target = TARGET.as_secs_f64();
+        let off_target = (target - delay) / target;
+        self.carry += GAIN * off_target * acked as f64 * mss as f64 / cwnd as f64;
+        let change = self.carry.trunc();
+        self.carry -= change;
+        let cwnd = (cwnd as f64 + change).max(0.0) as u32;
+        // an application that does not fill the window does not get to grow it
+        let max_allowed = flight
+            .saturating_add(acked)
+            .saturating_add(ALLOWED_INCREASE * mss);
+        cwnd.min(max_allowed).max(MIN_CWND * mss)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: u32 = 1000;
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn base_delay() {
+        let mut ledbat = Ledbat::default();
+        let start = Instant::now();
+        ledbat.on_rtt(50 * MS, start);
+        ledbat.on_rtt(40 * MS, start + 10 * MS);
+        assert_eq!(ledbat.base, [40 * MS]);
+        // a new minute starts a new entry
+        ledbat.on_rtt(60 * MS, start + BASE_INTERVAL);
+        assert_eq!(ledbat.base, [40 * MS, 60 * MS]);
+        // and the oldest one is forgotten after BASE_HISTORY minutes, so that a longer
+        // route shows up as the new base rather than as queuing
+        for minute in 2..=BASE_HISTORY as u32 {
+            ledbat.on_rtt(60 * MS, start + BASE_INTERVAL * minute);
+        }
+        assert_eq!(ledbat.base.len(), BASE_HISTORY);
+        assert_eq!(ledbat.base.iter().min(), Some(&(60 * MS)));
+    }
+
+    #[test]
+    fn current_delay() {
+        let mut ledbat = Ledbat::default();
+        let now = Instant::now();
+        ledbat.on_rtt(40 * MS, now);
+        for _ in 0..CURRENT_FILTER {
+            ledbat.on_rtt(90 * MS, now);
+        }
+        assert_eq!(ledbat.queuing_delay(), 50 * MS);
+        // a single lower sample is enough
+        ledbat.on_rtt(60 * MS, now);
+        assert_eq!(ledbat.queuing_delay(), 20 * MS);
+        assert!(!ledbat.leave_slow_start());
+        for _ in 0..CURRENT_FILTER {
+            ledbat.on_rtt(100 * MS, now);
+        }
+        assert!(ledbat.leave_slow_start());
+    }
+
+    /// A connection that measured `delay` of queuing.
+    fn delayed(delay: Duration) -> Ledbat {
+        let mut ledbat = Ledbat::default();
+        let now = Instant::now();
+        ledbat.on_rtt(20 * MS, now);
+        for _ in 0..CURRENT_FILTER {
+            ledbat.on_rtt(20 * MS + delay, now);
+        }
+        assert_eq!(ledbat.queuing_delay(), delay);
+        ledbat
+    }
+
+    #[test]
+    fn grows_below_target() {
+        // without queuing, by about a segment per window like Reno
+        let mut ledbat = delayed(Duration::ZERO);
+        let mut cwnd = 10 * MSS;
+        for _ in 0..10 {
+            cwnd = ledbat.on_ack(cwnd, MSS, cwnd, MSS);
+        }
+        assert!(cwnd > 10 * MSS + 9 * MSS / 10 && cwnd <= 11 * MSS, "{cwnd}");
+
+        // and slower the closer the delay is to the target
+        let mut ledbat = delayed(TARGET / 2);
+        let mut cwnd = 10 * MSS;
+        for _ in 0..10 {
+            cwnd = ledbat.on_ack(cwnd, MSS, cwnd, MSS);
+        }
+        assert!(cwnd > 10 * MSS && cwnd < 10 * MSS + MSS / 2, "{cwnd}");
+    }
+
+    #[test]
+    fn holds_at_target() {
+        let mut ledbat = delayed(TARGET);
+        assert_eq!(ledbat.on_ack(10 * MSS, MSS, 10 * MSS, MSS), 10 * MSS);
+    }
+
+    #[test]
+    fn decays_above_target() {
+        let mut ledbat = delayed(2 * TARGET);
+        let mut cwnd = 10 * MSS;
+        for _ in 0..10 {
+            cwnd = ledbat.on_ack(cwnd, MSS, cwnd, MSS);
+        }
+        assert!(cwnd < 10 * MSS - MSS / 2, "{cwnd}");
+        // but never below MIN_CWND
+        let mut ledbat = delayed(10 * TARGET);
+        let mut cwnd = 3 * MSS;
+        for _ in 0..10 {
+            cwnd = ledbat.on_ack(cwnd, MSS, cwnd, MSS);
+        }
+        assert_eq!(cwnd, MIN_CWND * MSS);
+    }
+
+    #[test]
+    fn limited_by_flight() {
+        let mut ledbat = delayed(Duration::ZERO);
+        // an application that keeps only two segments in flight
+        assert_eq!(
+            ledbat.on_ack(10 * MSS, MSS, 2 * MSS, MSS),
+            2 * MSS + MSS + ALLOWED_INCREASE * MSS
+        );
+    }
+}
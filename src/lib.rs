@@ -1,177 +1,1159 @@
+mod arp;
+mod async_io;
+mod bbr;
+mod congestion;
+mod cubic;
+mod device;
+mod ecn;
+mod eventfd;
+mod frag;
+mod icmp;
+mod ip;
+mod ledbat;
+mod link;
+mod persist;
+mod pmtu;
+pub mod poll;
+mod rto;
 pub mod tcp;
+mod timer;
+mod udp;
+mod wait;
+
+pub use congestion::{CongestionAlgorithm, CongestionControl};
 
 use std::{
     collections::{hash_map::Entry, HashMap, VecDeque},
     io::{self, Read, Write},
-    os::fd::AsRawFd,
-    sync::{Arc, Condvar, Mutex},
+    net::{IpAddr, SocketAddr},
+    os::fd::{AsRawFd, RawFd},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    task::Waker,
     thread,
+    time::{Duration, Instant},
 };
 
-use etherparse::Ipv4HeaderSlice;
 use tun_tap::{Iface, Mode};
 
-use crate::tcp::State;
+use crate::{eventfd::EventFd, link::Nic, tcp::Available, timer::DeadlineQueue, wait::WaitQueue};
 
-#[derive(Default)]
 struct FooBar {
     manager: Mutex<ConnectionManager>,
-    pending_var: Condvar,
-    rcv_var: Condvar,
+    timers: Mutex<DeadlineQueue>,
+    // in packet_loop's poll set, signalled when it has to recompute its timeout
+    wakeup: EventFd,
+    // addresses the stack answers for; empty means any unicast address
+    addresses: Vec<IpAddr>,
+    // answer ICMP and ICMPv6 echo requests
+    icmp_echo: bool,
+    // of the device, which no packet exceeds in either direction
+    mtu: usize,
+    // for new connections
+    options: tcp::Options,
+    // UDP datagrams for packet_loop to send, and the senders waiting for room in there
+    udp_out: Mutex<udp::SendQueue>,
+    udp_out_space: Condvar,
+    stats: Mutex<Stats>,
 }
 type InterfaceHandle = Arc<FooBar>;
 
-const SENDQUEUE_SIZE: usize = 1024;
+impl FooBar {
+    fn new(
+        addresses: Vec<IpAddr>,
+        icmp_echo: bool,
+        mtu: usize,
+        options: tcp::Options,
+    ) -> io::Result<Self> {
+        Ok(FooBar {
+            manager: Default::default(),
+            timers: Default::default(),
+            wakeup: EventFd::new()?,
+            addresses,
+            icmp_echo,
+            mtu,
+            options,
+            udp_out: Default::default(),
+            udp_out_space: Condvar::new(),
+            stats: Default::default(),
+        })
+    }
+
+    /// Make sure `packet_loop` calls `on_tick` for `c` by its next deadline.
+    ///
+    /// Must be called with the connection lock held, after anything that may give it something
+    /// to send.
+    fn schedule(&self, quad: tcp::Quad, c: &mut tcp::Connection) {
+        let Some(at) = c.next_deadline() else {
+            return;
+        };
+        if c.scheduled.is_some_and(|scheduled| scheduled <= at) {
+            // on_tick runs early enough already, and reschedules from there
+            return;
+        }
+        c.scheduled = Some(at);
+        let mut timers = self.timers.lock().unwrap();
+        let earliest = timers.next().is_none_or(|next| at < next);
+        timers.push(at, quad);
+        drop(timers);
+        if earliest {
+            self.wakeup.signal();
+        }
+    }
+
+    /// Remove `socket` from the connection table, unless another connection has taken its
+    /// place.
+    ///
+    /// Must be called without the connection lock held.
+    fn reap(&self, quad: tcp::Quad, socket: &ConnectionHandle) {
+        let mut m = self.manager.lock().unwrap();
+        if m.connections
+            .get(&quad)
+            .is_some_and(|s| Arc::ptr_eq(s, socket))
+        {
+            m.connections.remove(&quad);
+        }
+    }
+}
+
+/// A connection together with everyone waiting on it.
+///
+/// Shared by the connection table, `packet_loop` and the `TcpStream`, and locked on its own so
+/// that streams do not contend with each other, nor with packets for other connections.
+struct Socket {
+    c: Mutex<tcp::Connection>,
+    wq: WaitQueue,
+}
+type ConnectionHandle = Arc<Socket>;
+
+impl Socket {
+    fn new(c: tcp::Connection) -> ConnectionHandle {
+        Arc::new(Socket {
+            c: Mutex::new(c),
+            wq: WaitQueue::default(),
+        })
+    }
+}
+
+/// A bound UDP port together with everyone waiting on it, like [`Socket`] for connections.
+struct UdpPort {
+    b: Mutex<udp::Binding>,
+    wq: WaitQueue,
+}
+type UdpHandle = Arc<UdpPort>;
+
+/// Bytes a connection buffers for sending by default, which is as much as the peer can accept
+/// without window scaling: smaller, and the buffer rather than the windows would limit what is
+/// in flight.
+const SEND_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Connections each listener queues until they are accepted; SYNs beyond that are dropped.
+const ACCEPT_BACKLOG: usize = 128;
+
+/// First port handed out to actively opened connections (IANA dynamic range).
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Packet counters of an [`Interface`], named after the IP, ICMP, TCP and UDP MIBs (RFC 4293,
+/// RFC 4022, RFC 4113).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// packets read from the device
+    pub in_receives: u64,
+    /// packets dropped for a malformed header, a bad header checksum or a zero TTL
+    pub in_hdr_errors: u64,
+    /// packets dropped because they were not addressed to the interface
+    pub in_addr_errors: u64,
+    /// IPv6 fragments dropped, since only IPv4 fragments are reassembled
+    pub in_frag_drops: u64,
+    /// IPv4 fragments received to be reassembled
+    pub reasm_reqds: u64,
+    /// IPv4 datagrams reassembled
+    pub reasm_oks: u64,
+    /// IPv4 fragments dropped as malformed, overlapping or beyond the memory limits, and
+    /// datagrams that timed out before all their fragments arrived
+    pub reasm_fails: u64,
+    /// packets dropped because they were neither TCP, UDP nor ICMP
+    pub in_unknown_protos: u64,
+    /// packets handed to TCP, UDP or ICMP
+    pub in_delivers: u64,
+    /// ICMP and ICMPv6 messages received
+    pub icmp_in_msgs: u64,
+    /// ICMP and ICMPv6 messages dropped for being truncated or having a bad checksum
+    pub icmp_in_errors: u64,
+    /// echo requests received
+    pub icmp_in_echos: u64,
+    /// echo replies sent
+    pub icmp_out_echo_reps: u64,
+    /// TCP segments dropped for a bad checksum
+    pub tcp_in_csum_errors: u64,
+    /// ICMP errors ignored because they quote no segment in flight of any connection
+    pub tcp_out_of_window_icmps: u64,
+    /// UDP datagrams queued on a socket
+    pub udp_in_datagrams: u64,
+    /// UDP datagrams to ports no socket is bound to
+    pub udp_no_ports: u64,
+    /// UDP datagrams dropped for a bad length or checksum, or a full receive queue
+    pub udp_in_errors: u64,
+    /// UDP datagrams sent
+    pub udp_out_datagrams: u64,
+}
+
+impl Stats {
+    fn discarded(&mut self, discard: ip::Discard) {
+        match discard {
+            ip::Discard::Header => self.in_hdr_errors += 1,
+            ip::Discard::Address => self.in_addr_errors += 1,
+            ip::Discard::Fragment => self.in_frag_drops += 1,
+        }
+    }
+}
 
 pub struct Interface {
+    name: String,
+    mac: Option<[u8; 6]>,
     cm: Option<InterfaceHandle>,
-    jh: Option<thread::JoinHandle<()>>,
+    jh: Option<thread::JoinHandle<io::Result<()>>>,
 }
 
+/// The connection table and the listeners.
+///
+/// Only held to look up, add or remove entries; each connection has its own lock in [`Socket`].
 #[derive(Default)]
 struct ConnectionManager {
-    connections: HashMap<tcp::Quad, tcp::Connection>,
+    connections: HashMap<tcp::Quad, ConnectionHandle>,
     pending: HashMap<u16, VecDeque<tcp::Quad>>,
-    terminate: bool,
+    // the UDP sockets bound to each port, of different families or addresses
+    udp: HashMap<u16, Vec<UdpHandle>>,
+    // who is waiting on each listener
+    listeners: HashMap<u16, Arc<WaitQueue>>,
+    // tasks waiting in TcpListener::poll_accept
+    accept_wakers: HashMap<u16, Waker>,
+    next_port: u16,
+    // set by Interface::shutdown: connections left open by then are reset
+    terminate: Option<Instant>,
+}
+
+impl ConnectionManager {
+    fn ephemeral_port(&mut self, src: IpAddr, dst: SocketAddr) -> io::Result<u16> {
+        for _ in EPHEMERAL_PORT_START..=u16::MAX {
+            if self.next_port < EPHEMERAL_PORT_START {
+                self.next_port = EPHEMERAL_PORT_START;
+            }
+            let port = self.next_port;
+            self.next_port = self.next_port.wrapping_add(1);
+            let q = tcp::Quad {
+                src: (dst.ip(), dst.port()),
+                dst: (src, port),
+            };
+            if !self.pending.contains_key(&port) && !self.connections.contains_key(&q) {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "no ephemeral port available",
+        ))
+    }
+
+    /// Whether a UDP socket bound to `local` would receive datagrams meant for another one.
+    fn udp_in_use(&self, local: SocketAddr) -> bool {
+        let Some(sockets) = self.udp.get(&local.port()) else {
+            return false;
+        };
+        sockets.iter().any(|socket| {
+            let bound = socket.b.lock().unwrap().local;
+            bound.is_ipv4() == local.is_ipv4()
+                && (bound.ip() == local.ip()
+                    || bound.ip().is_unspecified()
+                    || local.ip().is_unspecified())
+        })
+    }
+
+    /// The socket to deliver a UDP datagram from `src` to `dst` to: a connected one before one
+    /// bound to `dst` before one bound to the unspecified address.
+    fn udp_socket(&self, src: SocketAddr, dst: SocketAddr) -> Option<UdpHandle> {
+        self.udp
+            .get(&dst.port())?
+            .iter()
+            .filter_map(|socket| {
+                let b = socket.b.lock().unwrap();
+                let specific = 2 * b.peer.is_some() as u8 + !b.local.ip().is_unspecified() as u8;
+                b.accepts(src, dst.ip()).then_some((specific, socket))
+            })
+            .max_by_key(|&(specific, _)| specific)
+            .map(|(_, socket)| socket.clone())
+    }
+
+    fn ephemeral_udp_port(&mut self) -> io::Result<u16> {
+        for _ in EPHEMERAL_PORT_START..=u16::MAX {
+            if self.next_port < EPHEMERAL_PORT_START {
+                self.next_port = EPHEMERAL_PORT_START;
+            }
+            let port = self.next_port;
+            self.next_port = self.next_port.wrapping_add(1);
+            if !self.udp.contains_key(&port) {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "no ephemeral port available",
+        ))
+    }
 }
 
+/// Resets all connections that are still open.
 impl Drop for Interface {
     fn drop(&mut self) {
-        self.cm.as_mut().unwrap().manager.lock().unwrap().terminate = true;
-        drop(self.cm.take());
-        self.jh
-            .take()
-            .expect("interface dropped more than once")
-            .join()
-            .unwrap();
+        if let Err(e) = self.shutdown(Duration::ZERO) {
+            eprintln!("packet loop has error: {e}");
+        }
+    }
+}
+
+/// Block on `var` until notified or until `deadline` passes.
+///
+/// Callers re-check their condition in a loop; once the deadline has passed this returns
+/// `TimedOut` instead of waiting again.
+fn wait_until<'a, T>(
+    var: &Condvar,
+    guard: MutexGuard<'a, T>,
+    deadline: Option<Instant>,
+) -> io::Result<MutexGuard<'a, T>> {
+    match deadline {
+        None => Ok(var.wait(guard).unwrap()),
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "operation timed out",
+                ));
+            }
+            let (guard, _) = var.wait_timeout(guard, deadline - now).unwrap();
+            Ok(guard)
+        }
     }
 }
 
-fn packet_loop(nic: Iface, cm: InterfaceHandle) -> io::Result<()> {
-    let mut buf = [0u8; 1504];
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "operation would block")
+}
+
+fn aborted() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "connection aborted")
+}
+
+fn shut_down() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "interface has been shut down")
+}
+
+/// The error of operations on a connection in `State::Closed`.
+fn closed(c: &tcp::Connection) -> io::Error {
+    c.error.map_or_else(aborted, io::Error::from)
+}
+
+fn check_timeout(dur: Option<Duration>) -> io::Result<()> {
+    if dur == Some(Duration::ZERO) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+    Ok(())
+}
+
+/// Abort `socket`, and wake everyone waiting on it so that they see the error.
+fn abort(nic: &Nic, socket: &Socket) {
+    let mut c = socket.c.lock().unwrap();
+    let before = c.availablity();
+    let a = c.abort(nic).unwrap_or_else(|e| {
+        eprintln!("failed to reset connection: {e}");
+        c.availablity()
+    });
+    let wakers = c.take_wakers(a);
+    socket.wq.notify(a);
+    socket.wq.signal(a - before);
+    drop(c);
+    wakers.for_each(Waker::wake);
+}
+
+/// Start closing every connection once `Interface::shutdown` has been called.
+///
+/// Connections that have not completed their handshake are reset right away.
+fn close_all(nic: &Nic, cm: &FooBar) {
+    let connections: Vec<_> = cm
+        .manager
+        .lock()
+        .unwrap()
+        .connections
+        .iter()
+        .map(|(q, socket)| (*q, socket.clone()))
+        .collect();
+    for (quad, socket) in connections {
+        let mut c = socket.c.lock().unwrap();
+        let closing = match c.state {
+            tcp::State::SynSent | tcp::State::SyncRcvd => false,
+            _ => c.close().is_ok(),
+        };
+        if closing {
+            // the FIN goes out from on_timers
+            cm.schedule(quad, &mut c);
+        } else {
+            drop(c);
+            abort(nic, &socket);
+        }
+    }
+}
+
+/// Whether every connection has had its FIN acknowledged, so nothing is lost by resetting it.
+fn all_closed(cm: &FooBar) -> bool {
+    let connections: Vec<_> = cm
+        .manager
+        .lock()
+        .unwrap()
+        .connections
+        .values()
+        .cloned()
+        .collect();
+    connections.iter().all(|socket| {
+        matches!(
+            socket.c.lock().unwrap().state,
+            tcp::State::FinWait2 | tcp::State::TimeWait | tcp::State::Closed
+        )
+    })
+}
+
+/// Reset all connections and fail everyone waiting on the interface, once `packet_loop` exits.
+fn abort_all(nic: &Nic, cm: &FooBar) {
+    let mut m = cm.manager.lock().unwrap();
+    // also when the loop stopped on an error, so that nothing new starts waiting
+    m.terminate.get_or_insert_with(Instant::now);
+    let connections: Vec<_> = m.connections.values().cloned().collect();
+    for wq in m.listeners.values() {
+        wq.notify(Available::READ);
+        wq.signal(Available::READ);
+    }
+    let wakers: Vec<_> = m.accept_wakers.drain().map(|(_, w)| w).collect();
+    let udp: Vec<_> = m.udp.values().flatten().cloned().collect();
+    drop(m);
+    wakers.into_iter().for_each(Waker::wake);
+
+    for socket in udp {
+        let mut b = socket.b.lock().unwrap();
+        let before = b.availability();
+        b.shut_down = true;
+        let a = b.availability();
+        socket.wq.notify(a);
+        socket.wq.signal(a - before);
+    }
+    // senders waiting for room in the queue see the interface shut down instead
+    let _udp_out = cm.udp_out.lock().unwrap();
+    cm.udp_out_space.notify_all();
+    drop(_udp_out);
+
+    for socket in connections {
+        abort(nic, &socket);
+    }
+}
+
+/// Hand an ICMP error to the connection or UDP socket it is about.
+fn on_icmp_error(cm: &FooBar, report: &icmp::Report) {
+    if report.protocol == udp::PROTO_UDP {
+        on_udp_error(cm, report);
+        return;
+    }
+    let socket = cm
+        .manager
+        .lock()
+        .unwrap()
+        .connections
+        .get(&report.quad)
+        .cloned();
+    let Some(socket) = socket else {
+        cm.stats.lock().unwrap().tcp_out_of_window_icmps += 1;
+        return;
+    };
+    let mut c = socket.c.lock().unwrap();
+    let before = c.availablity();
+    let Some(a) = c.on_icmp(report) else {
+        cm.stats.lock().unwrap().tcp_out_of_window_icmps += 1;
+        return;
+    };
+    // a lowered path MTU has everything in flight sent again
+    cm.schedule(report.quad, &mut c);
+    let wakers = c.take_wakers(a);
+    socket.wq.notify(a);
+    socket.wq.signal(a - before);
+    drop(c);
+    wakers.for_each(Waker::wake);
+}
+
+/// RFC 1122 S4.1.3.3: errors about datagrams to the peer of a connected UDP socket are returned
+/// by its next call; those about datagrams from unconnected ones are of no use to anyone.
+fn on_udp_error(cm: &FooBar, report: &icmp::Report) {
+    let icmp::Problem::Unreachable { kind, .. } = report.problem else {
+        // datagrams are fragmented to fit, so they never need to be sent smaller
+        return;
+    };
+    // the datagram went from quad.dst to quad.src, so it is as if the peer had sent it to us
+    let socket = cm
+        .manager
+        .lock()
+        .unwrap()
+        .udp_socket(report.quad.src.into(), report.quad.dst.into());
+    let Some(socket) = socket else {
+        return;
+    };
+    let mut b = socket.b.lock().unwrap();
+    if b.peer != Some(report.quad.src.into()) {
+        return;
+    }
+    let before = b.availability();
+    b.error = Some(kind);
+    let a = b.availability();
+    socket.wq.notify(a);
+    socket.wq.signal(a - before);
+}
+
+/// Hand a UDP datagram to the socket bound to its port, or tell the sender that there is none.
+///
+/// `datagram` is the whole IP datagram `packet` was parsed from, for the ICMP error to quote.
+fn on_udp(
+    nic: &Nic,
+    cm: &FooBar,
+    packet: &ip::Packet,
+    datagram: &[u8],
+    limit: &mut icmp::RateLimit,
+) {
+    let Some((src_port, dst_port, payload)) = udp::parse(packet) else {
+        cm.stats.lock().unwrap().udp_in_errors += 1;
+        return;
+    };
+    let src = SocketAddr::new(packet.header.source_addr(), src_port);
+    let dst = SocketAddr::new(packet.header.destination_addr(), dst_port);
+    let socket = cm.manager.lock().unwrap().udp_socket(src, dst);
+    let Some(socket) = socket else {
+        cm.stats.lock().unwrap().udp_no_ports += 1;
+        if let Err(e) = icmp::send_port_unreachable(nic, limit, datagram, cm.mtu) {
+            eprintln!("failed to report unreachable port: {e}");
+        }
+        return;
+    };
+    let mut b = socket.b.lock().unwrap();
+    let before = b.availability();
+    let delivered = b.deliver(src, payload);
+    let a = b.availability();
+    socket.wq.notify(a);
+    socket.wq.signal(a - before);
+    drop(b);
+    let mut stats = cm.stats.lock().unwrap();
+    if delivered {
+        stats.udp_in_datagrams += 1;
+    } else {
+        stats.udp_in_errors += 1;
+    }
+}
+
+/// Send the UDP datagrams that sockets queued, and let blocked senders queue more.
+fn send_udp(nic: &Nic, cm: &FooBar) {
+    let mut queue = cm.udp_out.lock().unwrap();
+    if queue.datagrams.is_empty() {
+        return;
+    }
+    let queued = std::mem::take(&mut *queue);
+    cm.udp_out_space.notify_all();
+    drop(queue);
+    for datagram in queued.datagrams {
+        match udp::send(nic, &datagram, cm.mtu) {
+            Ok(()) => cm.stats.lock().unwrap().udp_out_datagrams += 1,
+            Err(e) => eprintln!("failed to send udp datagram: {e}"),
+        }
+    }
+}
+
+/// Run `on_tick` for every connection whose deadline has passed.
+fn on_timers(nic: &Nic, cm: &FooBar) -> io::Result<()> {
+    let expired = cm.timers.lock().unwrap().expired(Instant::now());
+    for (at, quad) in expired {
+        let Some(socket) = cm.manager.lock().unwrap().connections.get(&quad).cloned() else {
+            continue;
+        };
+        let mut c = socket.c.lock().unwrap();
+        if c.scheduled != Some(at) {
+            // superseded by a later schedule() call
+            continue;
+        }
+        c.scheduled = None;
+        let before = c.availablity();
+        c.on_tick(nic)?;
+        cm.schedule(quad, &mut c);
+        if c.is_finished() {
+            drop(c);
+            cm.reap(quad, &socket);
+            continue;
+        }
+        let a = c.availablity();
+        if a == before {
+            continue;
+        }
+        // e.g. the handshake timed out
+        let closed = matches!(c.state, tcp::State::Closed);
+        let wakers = c.take_wakers(a);
+        socket.wq.notify(a);
+        socket.wq.signal(a - before);
+        drop(c);
+        wakers.for_each(Waker::wake);
+        if !closed {
+            continue;
+        }
+        let mut m = cm.manager.lock().unwrap();
+        if let Some(pending) = m.pending.get_mut(&quad.dst.1) {
+            if let Some(i) = pending.iter().position(|q| *q == quad) {
+                // nobody accepted it, so nobody else will remove it
+                pending.remove(i);
+                m.connections.remove(&quad);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Milliseconds until `deadline` for poll(2), rounded up so that we never wake up too early.
+fn poll_timeout(deadline: Option<Instant>) -> nix::libc::c_int {
+    match deadline {
+        None => -1,
+        Some(deadline) => {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            wait.as_nanos()
+                .div_ceil(1_000_000)
+                .min(nix::libc::c_int::MAX as u128) as nix::libc::c_int
+        }
+    }
+}
+
+fn packet_loop(nic: &Nic, cm: &FooBar) -> io::Result<()> {
+    let mut buf = vec![0u8; nic.header_len() + cm.mtu];
+    let mut fragments = frag::Reassembly::default();
+    let mut icmp_errors = icmp::RateLimit::default();
+    let mut closing = false;
     loop {
+        let terminate = cm.manager.lock().unwrap().terminate;
+        if let Some(deadline) = terminate {
+            if !closing {
+                close_all(nic, cm);
+                closing = true;
+            }
+            if Instant::now() >= deadline || all_closed(cm) {
+                return Ok(());
+            }
+        }
+
         // we want to read from nic, but we want to make sure that we'll wake up when then next
-        // timer has to be triggered!
-        let mut pfd = [nix::poll::PollFd::new(
-            nic.as_raw_fd(),
-            nix::poll::PollFlags::POLLIN,
-        )];
-        let fut = nix::poll::poll(&mut pfd, 1).map_err(io::Error::from)?;
-        assert_ne!(fut, -1);
-        if fut == 0 {
-            let mut mg = cm.manager.lock().unwrap();
-            for connection in mg.connections.values_mut() {
-                connection.on_tick(&nic)?;
+        // timer has to be triggered, when fragments time out, when ARP requests have to be
+        // repeated, or when we have to give up on closing gracefully!
+        let next = cm.timers.lock().unwrap().next();
+        let timeout = poll_timeout(
+            [
+                next,
+                fragments.next_expiry(),
+                nic.next_deadline(),
+                terminate,
+            ]
+            .into_iter()
+            .flatten()
+            .min(),
+        );
+        let mut pfd = [
+            nix::poll::PollFd::new(nic.as_raw_fd(), nix::poll::PollFlags::POLLIN),
+            nix::poll::PollFd::new(cm.wakeup.as_raw_fd(), nix::poll::PollFlags::POLLIN),
+        ];
+        nix::poll::poll(&mut pfd, timeout).map_err(io::Error::from)?;
+        let nic_ready = pfd[0]
+            .revents()
+            .is_some_and(|r| r.contains(nix::poll::PollFlags::POLLIN));
+        if pfd[1]
+            .revents()
+            .is_some_and(|r| r.contains(nix::poll::PollFlags::POLLIN))
+        {
+            cm.wakeup.drain();
+        }
+
+        on_timers(nic, cm)?;
+        send_udp(nic, cm);
+        nic.on_tick()?;
+        for first in fragments.expire(Instant::now()) {
+            cm.stats.lock().unwrap().reasm_fails += 1;
+            if let Some(first) = first {
+                if let Err(e) = icmp::send_reassembly_timeout(nic, &mut icmp_errors, &first, cm.mtu)
+                {
+                    eprintln!("failed to report reassembly timeout: {e}");
+                }
             }
+        }
+        if !nic_ready {
             continue;
         }
-        assert_eq!(fut, 1);
 
-        // TODO: set a timeout for this recv for TCP timers or ConnectionManager::terminate
         let nbytes = nic.recv(buf.as_mut_slice())?;
         if nbytes == 0 {
             break;
         }
-        //TODO: if self.terminate && Arc::get_strong_ref(cm) == 1; then tear down all connections and return
-
-        // if s/without_packet_info/new/:
-        // let eth_flags = u16::from_be_bytes([buf[0], buf[1]]);
-        // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
-        // if eth_proto != 0x0800 {
-        //     // no ipv4
-        //     continue;
-        // }
-
-        match Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
-            Ok(iph) => {
-                let proto = iph.protocol();
-                if proto != 0x06 {
-                    // not tcp
-                    continue;
+        let Some(received) = nic.ip_packet(&buf[..nbytes]) else {
+            // ARP, or a frame for someone else
+            continue;
+        };
+
+        cm.stats.lock().unwrap().in_receives += 1;
+        let datagram;
+        // the whole datagram, for ICMP errors to quote
+        let mut raw = received;
+        let packet = match ip::parse(received, &cm.addresses) {
+            Ok(packet) => packet,
+            Err(ip::Discard::Fragment) if received[0] >> 4 == 4 => {
+                let outcome = fragments.insert(received, Instant::now());
+                let mut stats = cm.stats.lock().unwrap();
+                stats.reasm_reqds += 1;
+                match outcome {
+                    frag::Outcome::Complete(complete) => {
+                        stats.reasm_oks += 1;
+                        datagram = complete;
+                    }
+                    frag::Outcome::Pending => continue,
+                    frag::Outcome::Failed => {
+                        stats.reasm_fails += 1;
+                        continue;
+                    }
+                }
+                drop(stats);
+                raw = &datagram;
+                match ip::parse(&datagram, &cm.addresses) {
+                    Ok(packet) => packet,
+                    Err(discard) => {
+                        cm.stats.lock().unwrap().discarded(discard);
+                        continue;
+                    }
+                }
+            }
+            Err(discard) => {
+                cm.stats.lock().unwrap().discarded(discard);
+                continue;
+            }
+        };
+        let v4 = matches!(packet.header, ip::IpHeaderSlice::V4(_));
+        let icmp = if v4 {
+            icmp::PROTO_ICMP
+        } else {
+            icmp::PROTO_ICMPV6
+        };
+        if packet.protocol == icmp {
+            let outcome = icmp::on_packet(nic, &packet, cm.icmp_echo, cm.mtu).unwrap_or_else(|e| {
+                eprintln!("failed to answer echo request: {e}");
+                icmp::Outcome::Echo { replied: false }
+            });
+            let mut stats = cm.stats.lock().unwrap();
+            stats.in_delivers += 1;
+            stats.icmp_in_msgs += 1;
+            match outcome {
+                icmp::Outcome::Error => stats.icmp_in_errors += 1,
+                icmp::Outcome::Echo { replied } => {
+                    stats.icmp_in_echos += 1;
+                    stats.icmp_out_echo_reps += replied as u64;
+                }
+                icmp::Outcome::Report(report) => {
+                    drop(stats);
+                    on_icmp_error(cm, &report);
                 }
+                icmp::Outcome::Ignored => {}
+            }
+            continue;
+        }
+        if packet.protocol == udp::PROTO_UDP {
+            cm.stats.lock().unwrap().in_delivers += 1;
+            on_udp(nic, cm, &packet, raw, &mut icmp_errors);
+            continue;
+        }
+        if packet.protocol != 0x06 {
+            // not tcp
+            cm.stats.lock().unwrap().in_unknown_protos += 1;
+            continue;
+        }
+        cm.stats.lock().unwrap().in_delivers += 1;
+        if !ip::transport_checksum_ok(&packet) {
+            cm.stats.lock().unwrap().tcp_in_csum_errors += 1;
+            continue;
+        }
 
-                let iph_len = iph.slice().len();
-                match etherparse::TcpHeaderSlice::from_slice(&buf[iph_len..nbytes]) {
-                    Ok(tcph) => {
-                        let datai = iph_len + tcph.slice().len();
-                        let mut mg = cm.manager.lock().unwrap();
-                        let m = &mut *mg;
-                        let q = tcp::Quad {
-                            src: (iph.source_addr(), tcph.source_port()),
-                            dst: (iph.destination_addr(), tcph.destination_port()),
-                        };
-                        match m.connections.entry(q) {
-                            Entry::Occupied(mut c) => {
-                                eprintln!("got packet for known quad: {q:?}");
-                                let a = c
-                                    .get_mut()
-                                    .on_packet(&nic, tcph, &buf[datai..nbytes])
-                                    .unwrap();
-                                //TODO compare before/after
-                                drop(mg);
-                                if a.contains(tcp::Available::READ) {
-                                    cm.rcv_var.notify_all();
-                                }
-                                if a.contains(tcp::Available::WRITE) {
-                                    // cm.snd_var.notify_all();
-                                }
+        let iph = &packet.header;
+        match etherparse::TcpHeaderSlice::from_slice(packet.payload) {
+            Ok(tcph) => {
+                let data = &packet.payload[tcph.slice().len()..];
+                let mut mg = cm.manager.lock().unwrap();
+                let m = &mut *mg;
+                let q = tcp::Quad {
+                    src: (iph.source_addr(), tcph.source_port()),
+                    dst: (iph.destination_addr(), tcph.destination_port()),
+                };
+                match m.connections.entry(q) {
+                    Entry::Occupied(socket) => {
+                        eprintln!("got packet for known quad: {q:?}");
+                        let socket = socket.get().clone();
+                        drop(mg);
+                        let mut c = socket.c.lock().unwrap();
+                        let before = c.availablity();
+                        let a = c.on_packet(nic, tcph, data, iph.ecn()).unwrap();
+                        cm.schedule(q, &mut c);
+                        if c.is_finished() {
+                            drop(c);
+                            cm.reap(q, &socket);
+                            continue;
+                        }
+                        let wakers = c.take_wakers(a);
+                        socket.wq.notify(a);
+                        // readiness that was already there has been reported before
+                        socket.wq.signal(a - before);
+                        drop(c);
+                        wakers.for_each(Waker::wake);
+                    }
+                    Entry::Vacant(e) => {
+                        eprintln!("got packet for unknown quad: {q:?}");
+                        if m.terminate.is_some() {
+                            // shutting down, no new connections
+                            continue;
+                        }
+                        if let Some(pending) = m.pending.get_mut(&tcph.destination_port()) {
+                            eprintln!("got packet for pending unknown quad: {q:?}");
+                            if pending.len() >= ACCEPT_BACKLOG {
+                                // as in Linux: the peer sends its SYN again, and there may be
+                                // room by then
+                                continue;
                             }
-                            Entry::Vacant(e) => {
-                                eprintln!("got packet for unknown quad: {q:?}");
-                                if let Some(pending) = m.pending.get_mut(&tcph.destination_port()) {
-                                    eprintln!("got packet for pending unknown quad: {q:?}");
-                                    if let Some(c) = tcp::Connection::accept(
-                                        &nic,
-                                        iph,
-                                        tcph,
-                                        &buf[datai..nbytes],
-                                    )
+                            if let Some(c) =
+                                tcp::Connection::accept(nic, iph, tcph, data, cm.mtu, cm.options)
                                     .unwrap()
-                                    {
-                                        e.insert(c);
-                                        pending.push_back(q);
-                                        drop(mg);
-                                        cm.pending_var.notify_all();
-                                        //TODO: wake up pending accept()
-                                    }
+                            {
+                                let socket = e.insert(Socket::new(c)).clone();
+                                pending.push_back(q);
+                                if let Some(wq) = m.listeners.get(&q.dst.1) {
+                                    wq.notify(Available::READ);
+                                    wq.signal(Available::READ);
+                                }
+                                let waker = m.accept_wakers.remove(&q.dst.1);
+                                drop(mg);
+                                // for the retransmission of the SYN-ACK
+                                cm.schedule(q, &mut socket.c.lock().unwrap());
+                                if let Some(waker) = waker {
+                                    waker.wake();
                                 }
                             }
                         }
                     }
-                    Err(e) => {
-                        eprintln!("ignoring weird tcp packet {:?}", e);
-                    }
                 }
             }
-            Err(_) => {
-                //   eprintln!("ignoring weird ip packet {:?}", e);
+            Err(e) => {
+                eprintln!("ignoring weird tcp packet {:?}", e);
             }
         }
     }
     Ok(())
 }
 
-impl Interface {
-    pub fn new() -> io::Result<Self> {
-        let nic = Iface::without_packet_info("tun0", Mode::Tun)?;
-        let cm: InterfaceHandle = Default::default();
+/// Options for creating an [`Interface`].
+///
+/// By default the stack attaches to `tun0` and leaves its configuration to the host. Everything
+/// set here is applied to the device before the interface starts processing packets, which needs
+/// `CAP_NET_ADMIN`.
+#[derive(Debug, Clone)]
+pub struct InterfaceBuilder {
+    name: Option<String>,
+    addresses: Vec<(IpAddr, u8)>,
+    local_addresses: Vec<IpAddr>,
+    mtu: Option<u32>,
+    persist: Option<bool>,
+    up: bool,
+    icmp_echo: bool,
+    congestion: CongestionAlgorithm,
+    ecn: bool,
+    send_buffer: usize,
+    tap: bool,
+    mac: Option<[u8; 6]>,
+    bridge: Option<String>,
+}
+
+impl Default for InterfaceBuilder {
+    fn default() -> Self {
+        InterfaceBuilder {
+            name: None,
+            addresses: Vec::new(),
+            local_addresses: Vec::new(),
+            mtu: None,
+            persist: None,
+            up: false,
+            icmp_echo: true,
+            congestion: CongestionAlgorithm::default(),
+            ecn: false,
+            send_buffer: SEND_BUFFER_SIZE,
+            tap: false,
+            mac: None,
+            bridge: None,
+        }
+    }
+}
+
+impl InterfaceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Name of the device to create or attach to; `tun0`, or `tap0` in TAP mode, by default.
+    ///
+    /// A `%d` in the name is replaced by the first free number; see [`Interface::name`].
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Assign `addr` with a `prefix` bit netmask to the device; may be called once per family.
+    ///
+    /// This is the host's end of the link, like `ip addr add`; the stack answers for the other
+    /// addresses routed through the device.
+    pub fn address(mut self, addr: impl Into<IpAddr>, prefix: u8) -> Self {
+        self.addresses.push((addr.into(), prefix));
+        self
+    }
+
+    /// Add an address of the stack itself; may be called several times.
+    ///
+    /// Packets to other addresses are dropped, and connections can only be opened from these
+    /// addresses. Without any, the stack answers for every unicast address.
+    pub fn local_address(mut self, addr: impl Into<IpAddr>) -> Self {
+        self.local_addresses.push(addr.into());
+        self
+    }
+
+    pub fn mtu(mut self, mtu: u32) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Keep the device after the interface is dropped, or with `false`, remove a device that
+    /// was made persistent before once the interface is dropped.
+    pub fn persist(mut self, persist: bool) -> Self {
+        self.persist = Some(persist);
+        self
+    }
+
+    /// Bring the device up once it is configured, like `ip link set up`.
+    pub fn up(mut self, up: bool) -> Self {
+        self.up = up;
+        self
+    }
+
+    /// Answer ICMP and ICMPv6 echo requests, so that the stack's addresses can be pinged; on by
+    /// default.
+    pub fn icmp_echo(mut self, icmp_echo: bool) -> Self {
+        self.icmp_echo = icmp_echo;
+        self
+    }
+
+    /// The congestion control algorithm of new connections; NewReno by default. Streams can
+    /// switch with [`TcpStream::set_congestion_algorithm`].
+    pub fn congestion_algorithm(mut self, algorithm: CongestionAlgorithm) -> Self {
+        self.congestion = algorithm;
+        self
+    }
+
+    /// Negotiate Explicit Congestion Notification (RFC 3168) on new connections, so that routers
+    /// along the path can mark their packets instead of dropping them; off by default.
+    pub fn ecn(mut self, ecn: bool) -> Self {
+        self.ecn = ecn;
+        self
+    }
+
+    /// Bytes each new connection buffers for sending, 64 KiB by default; writes block once the
+    /// buffer is full. Streams can change theirs with [`TcpStream::set_send_buffer_size`].
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer = size;
+        self
+    }
+
+    /// Use a TAP device, which carries Ethernet frames, instead of a TUN device.
+    ///
+    /// The stack then answers ARP requests for its local addresses, which have to be set with
+    /// [`local_address`](Self::local_address) and, for lack of neighbor discovery, be IPv4
+    /// only. Peers are only reached on the local segment, since there are no routes.
+    pub fn tap(mut self, tap: bool) -> Self {
+        self.tap = tap;
+        self
+    }
+
+    /// Hardware address of the stack in TAP mode; a random locally administered one by default.
+    ///
+    /// This is the address of the stack itself, not of the host's end of the device.
+    pub fn mac_address(mut self, mac: [u8; 6]) -> Self {
+        self.mac = Some(mac);
+        self
+    }
+
+    /// Add the device to the Linux bridge `bridge`, like `ip link set master`; TAP mode only.
+    ///
+    /// This puts the stack on the same segment as whatever else is in the bridge, such as
+    /// the TAP devices of virtual machines.
+    pub fn bridge(mut self, bridge: impl Into<String>) -> Self {
+        self.bridge = Some(bridge.into());
+        self
+    }
+
+    pub fn build(self) -> io::Result<Interface> {
+        let (mode, name) = if self.tap {
+            (Mode::Tap, self.name.as_deref().unwrap_or("tap0"))
+        } else {
+            (Mode::Tun, self.name.as_deref().unwrap_or("tun0"))
+        };
+        let mut local_v4 = Vec::new();
+        for addr in &self.local_addresses {
+            match addr {
+                IpAddr::V4(addr) => local_v4.push(*addr),
+                IpAddr::V6(_) if self.tap => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "IPv6 needs neighbor discovery, which TAP mode lacks",
+                    ))
+                }
+                IpAddr::V6(_) => {}
+            }
+        }
+        if self.tap && local_v4.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TAP mode needs a local IPv4 address to answer ARP for",
+            ));
+        }
+        if !self.tap && (self.mac.is_some() || self.bridge.is_some()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "hardware addresses and bridges need TAP mode",
+            ));
+        }
+
+        let iface = Iface::without_packet_info(name, mode)?;
+        let name = iface.name().to_string();
+        let (nic, mac) = if self.tap {
+            let mac = match self.mac {
+                Some(mac) => mac,
+                None => random_mac()?,
+            };
+            (Nic::tap(iface, mac, local_v4), Some(mac))
+        } else {
+            (Nic::tun(iface), None)
+        };
+        if let Some(persist) = self.persist {
+            device::set_persist(nic.as_raw_fd(), persist)?;
+        }
+        if let Some(mtu) = self.mtu {
+            device::set_mtu(&name, mtu)?;
+        }
+        for (addr, prefix) in self.addresses {
+            device::set_address(&name, addr, prefix)?;
+        }
+        if let Some(bridge) = &self.bridge {
+            device::add_to_bridge(bridge, &name)?;
+        }
+        if self.up {
+            device::set_up(&name)?;
+        }
+        // the MTU may also have been set from outside, and packets are never larger
+        let mtu = device::mtu(&name)? as usize;
+
+        let cm: InterfaceHandle = Arc::new(FooBar::new(
+            self.local_addresses,
+            self.icmp_echo,
+            mtu,
+            tcp::Options {
+                congestion: self.congestion,
+                ecn: self.ecn,
+                send_buffer: self.send_buffer,
+            },
+        )?);
         let jh = {
             let cm = cm.clone();
             thread::spawn(move || {
-                if let Err(e) = packet_loop(nic, cm) {
-                    eprintln!("packet loop has error: {e}");
-                }
+                let result = packet_loop(&nic, &cm);
+                abort_all(&nic, &cm);
+                result
             })
         };
 
-        Ok(Self {
+        Ok(Interface {
+            name,
+            mac,
             cm: Some(cm),
             jh: Some(jh),
         })
     }
+}
+
+/// A random unicast address, marked as locally administered so that it cannot clash with
+/// those assigned to hardware.
+fn random_mac() -> io::Result<[u8; 6]> {
+    let mut mac = [0; 6];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut mac)?;
+    mac[0] = (mac[0] & !0x01) | 0x02;
+    Ok(mac)
+}
+
+impl Interface {
+    /// Attach to `tun0`, as configured by the host.
+    pub fn new() -> io::Result<Self> {
+        InterfaceBuilder::new().build()
+    }
+
+    pub fn builder() -> InterfaceBuilder {
+        InterfaceBuilder::new()
+    }
+
+    /// Name of the device, as assigned by the kernel.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Hardware address of the stack, in TAP mode.
+    pub fn mac_address(&self) -> Option<[u8; 6]> {
+        self.mac
+    }
 
+    pub fn stats(&self) -> Stats {
+        *self.cm.as_ref().unwrap().stats.lock().unwrap()
+    }
+
+    /// Close all connections and stop the interface.
+    ///
+    /// Connections get up to `grace` to deliver what has been written and to have their FIN
+    /// acknowledged; those still open after that are reset. Blocked and later operations on
+    /// streams and listeners of this interface then fail, and new ones cannot be created.
+    ///
+    /// Returns once the packet loop has exited, with the error that stopped it, if any.
+    pub fn shutdown(&mut self, grace: Duration) -> io::Result<()> {
+        let Some(jh) = self.jh.take() else {
+            return Ok(());
+        };
+        let cm = self.cm.as_ref().unwrap();
+        cm.manager
+            .lock()
+            .unwrap()
+            .terminate
+            .get_or_insert(Instant::now() + grace);
+        cm.wakeup.signal();
+        jh.join()
+            .unwrap_or_else(|_| Err(io::Error::other("packet loop panicked")))
+    }
+
+    /// Listen on `port` of every local address, IPv4 and IPv6 alike.
     pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
         let mut cm = self.cm.as_mut().unwrap().manager.lock().unwrap();
+        if cm.terminate.is_some() {
+            return Err(shut_down());
+        }
         match cm.pending.entry(port) {
             Entry::Vacant(v) => {
                 v.insert(VecDeque::new());
@@ -183,151 +1165,749 @@ impl Interface {
                 ));
             }
         }
+        let wq = Arc::new(WaitQueue::default());
+        cm.listeners.insert(port, wq.clone());
         drop(cm);
         Ok(TcpListener {
             port,
             cm: self.cm.as_ref().unwrap().clone(),
+            wq,
+            nonblocking: false,
+        })
+    }
+
+    /// Actively open a connection from the local address `src` to `dst`.
+    ///
+    /// Both must be of the same address family. Blocks until the three-way handshake completes.
+    pub fn connect(
+        &mut self,
+        src: impl Into<IpAddr>,
+        dst: impl Into<SocketAddr>,
+    ) -> io::Result<TcpStream> {
+        self.do_connect(src.into(), dst.into(), None)
+    }
+
+    /// Like [`Interface::connect`], but gives up with `TimedOut` if the handshake has not
+    /// completed within `timeout`.
+    pub fn connect_timeout(
+        &mut self,
+        src: impl Into<IpAddr>,
+        dst: impl Into<SocketAddr>,
+        timeout: Duration,
+    ) -> io::Result<TcpStream> {
+        check_timeout(Some(timeout))?;
+        self.do_connect(src.into(), dst.into(), Some(Instant::now() + timeout))
+    }
+
+    /// Bind a UDP socket to `addr`, which is either a local address or the unspecified address
+    /// of a family, for all local addresses of it. Port 0 picks a free ephemeral port.
+    ///
+    /// Sockets bound to an unspecified address send from the first local address of the family
+    /// of the destination, so they can only send if the interface has one.
+    pub fn bind_udp(&mut self, addr: impl Into<SocketAddr>) -> io::Result<UdpSocket> {
+        let mut local = addr.into();
+        let ih = self.cm.as_ref().unwrap().clone();
+        if !local.ip().is_unspecified() && !ip::is_local(&ih.addresses, local.ip()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "not an address of the interface",
+            ));
+        }
+        let mut cm = ih.manager.lock().unwrap();
+        if cm.terminate.is_some() {
+            return Err(shut_down());
+        }
+        if local.port() == 0 {
+            local.set_port(cm.ephemeral_udp_port()?);
+        } else if cm.udp_in_use(local) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "port already bound",
+            ));
+        }
+        let socket = Arc::new(UdpPort {
+            b: Mutex::new(udp::Binding::new(local)),
+            wq: WaitQueue::default(),
+        });
+        cm.udp.entry(local.port()).or_default().push(socket.clone());
+        drop(cm);
+        Ok(UdpSocket {
+            port: local.port(),
+            cm: ih,
+            socket,
+            read_timeout: None,
+            write_timeout: None,
+            nonblocking: false,
         })
     }
+
+    fn do_connect(
+        &mut self,
+        src: IpAddr,
+        dst: SocketAddr,
+        deadline: Option<Instant>,
+    ) -> io::Result<TcpStream> {
+        if src.is_ipv4() != dst.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "source and destination are of different address families",
+            ));
+        }
+        let ih = self.cm.as_ref().unwrap().clone();
+        if !ip::is_local(&ih.addresses, src) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "not an address of the interface",
+            ));
+        }
+        let mut cm = ih.manager.lock().unwrap();
+        if cm.terminate.is_some() {
+            return Err(shut_down());
+        }
+        let port = cm.ephemeral_port(src, dst)?;
+        // quads are keyed from the point of view of incoming packets
+        let quad = tcp::Quad {
+            src: (dst.ip(), dst.port()),
+            dst: (src, port),
+        };
+        let socket = Socket::new(tcp::Connection::connect(
+            (src, port),
+            (dst.ip(), dst.port()),
+            ih.mtu,
+            ih.options,
+        ));
+        cm.connections.insert(quad, socket.clone());
+        drop(cm);
+
+        let mut c = socket.c.lock().unwrap();
+        // the SYN goes out from packet_loop
+        ih.schedule(quad, &mut c);
+        loop {
+            if let tcp::State::Closed = c.state {
+                let e = closed(&c);
+                drop(c);
+                ih.manager.lock().unwrap().connections.remove(&quad);
+                return Err(e);
+            }
+            if c.state.is_synchronized() {
+                drop(c);
+                return Ok(TcpStream {
+                    quad,
+                    cm: ih,
+                    socket,
+                    read_timeout: None,
+                    write_timeout: None,
+                    nonblocking: false,
+                });
+            }
+
+            c = match wait_until(&socket.wq.writers, c, deadline) {
+                Ok(c) => c,
+                Err(e) => {
+                    // e.g. a host unreachable is more telling than the timeout it led to
+                    let e = socket
+                        .c
+                        .lock()
+                        .unwrap()
+                        .soft_error
+                        .map_or(e, io::Error::from);
+                    ih.manager.lock().unwrap().connections.remove(&quad);
+                    return Err(e);
+                }
+            };
+        }
+    }
 }
 
 pub struct TcpStream {
     quad: tcp::Quad,
     cm: InterfaceHandle,
+    socket: ConnectionHandle,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nonblocking: bool,
 }
 
 impl Read for TcpStream {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut cm = self.cm.manager.lock().unwrap();
+        let deadline = self.read_timeout.map(|t| Instant::now() + t);
+        let mut c = self.socket.c.lock().unwrap();
         loop {
-            let c = cm.connections.get_mut(&self.quad).ok_or(io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "stream was terminated unexpectedly",
-            ))?;
-            if c.is_rev_closed() && c.incoming.is_empty() {
-                // no more data to read, no need to block, because there won't be any more
-                return Ok(0);
-            }
-
-            if !c.incoming.is_empty() {
-                //TODO: detect FIN and return nread == 0
-                let mut nread = 0;
-                let (head, tail) = c.incoming.as_slices();
-                let hread = buf.len().min(head.len());
-                buf[..hread].copy_from_slice(&head[..hread]);
-                nread += hread;
-                let tread = (buf.len() - nread).min(tail.len());
-                buf[hread..hread + tread].copy_from_slice(&tail[..tread]);
-                nread += tread;
-                drop(c.incoming.drain(..nread));
+            if let Some(nread) = self.try_read(&mut c, buf)? {
                 return Ok(nread);
             }
 
-            cm = self.cm.rcv_var.wait(cm).unwrap();
+            if self.nonblocking {
+                self.socket.wq.drain_fd();
+                return Err(would_block());
+            }
+            c = wait_until(&self.socket.wq.readers, c, deadline)?;
         }
     }
 }
 
 impl Write for TcpStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut cm = self.cm.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or(io::Error::new(
-            io::ErrorKind::ConnectionAborted,
-            "stream was terminated unexpectedly",
-        ))?;
-        if c.unacked.len() >= SENDQUEUE_SIZE {
-            //TODO: block
+        let deadline = self.write_timeout.map(|t| Instant::now() + t);
+        let mut c = self.socket.c.lock().unwrap();
+        loop {
+            if let Some(nwrite) = self.try_write(&mut c, buf)? {
+                return Ok(nwrite);
+            }
+
+            if self.nonblocking {
+                self.socket.wq.drain_fd();
+                return Err(would_block());
+            }
+            c = wait_until(&self.socket.wq.writers, c, deadline)?;
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let deadline = self.write_timeout.map(|t| Instant::now() + t);
+        let mut c = self.socket.c.lock().unwrap();
+        loop {
+            if self.try_flush(&mut c)? {
+                return Ok(());
+            }
+
+            if self.nonblocking {
+                self.socket.wq.drain_fd();
+                return Err(would_block());
+            }
+            c = wait_until(&self.socket.wq.writers, c, deadline)?;
+        }
+    }
+}
+
+impl TcpStream {
+    pub fn shutdown(&self, _how: std::net::Shutdown) -> io::Result<()> {
+        let mut c = self.socket.c.lock().unwrap();
+        c.close()?;
+        // the FIN goes out from packet_loop
+        self.cm.schedule(self.quad, &mut c);
+        Ok(())
+    }
+
+    /// Read whatever has been received; `None` means there is nothing to read yet.
+    fn try_read(&self, c: &mut tcp::Connection, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        if let tcp::State::Closed = c.state {
+            if c.incoming.is_empty() {
+                return Err(closed(c));
+            }
+        }
+
+        if c.is_rev_closed() && c.incoming.is_empty() {
+            // no more data to read, no need to block, because there won't be any more
+            return Ok(Some(0));
+        }
+
+        if c.incoming.is_empty() {
+            return Ok(None);
+        }
+
+        //TODO: detect FIN and return nread == 0
+        let mut nread = 0;
+        let (head, tail) = c.incoming.as_slices();
+        let hread = buf.len().min(head.len());
+        buf[..hread].copy_from_slice(&head[..hread]);
+        nread += hread;
+        let tread = (buf.len() - nread).min(tail.len());
+        buf[hread..hread + tread].copy_from_slice(&tail[..tread]);
+        nread += tread;
+        drop(c.incoming.drain(..nread));
+        Ok(Some(nread))
+    }
+
+    /// Queue as much of `buf` as fits; `None` means the send queue is full.
+    fn try_write(&self, c: &mut tcp::Connection, buf: &[u8]) -> io::Result<Option<usize>> {
+        if let tcp::State::Closed = c.state {
+            return Err(closed(c));
+        }
+
+        if c.closed {
             return Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many bytes buffered",
+                io::ErrorKind::BrokenPipe,
+                "stream was shut down for writing",
             ));
         }
 
-        let nwrite = buf.len().min(SENDQUEUE_SIZE - c.unacked.len());
+        if c.unacked.len() >= c.send_buffer {
+            return Ok(None);
+        }
+
+        let nwrite = buf.len().min(c.send_buffer - c.unacked.len());
         c.unacked.extend(&buf[..nwrite]);
-        println!("{:x?}", c.unacked);
-        // TODO: wrak up writer
-        Ok(nwrite)
+        self.cm.schedule(self.quad, c);
+        Ok(Some(nwrite))
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        let mut cm = self.cm.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or(io::Error::new(
-            io::ErrorKind::ConnectionAborted,
-            "stream was terminated unexpectedly",
-        ))?;
+    /// Whether everything written so far has been acknowledged.
+    fn try_flush(&self, c: &mut tcp::Connection) -> io::Result<bool> {
+        if let tcp::State::Closed = c.state {
+            return Err(closed(c));
+        }
+        Ok(c.unacked.is_empty())
+    }
 
-        if c.unacked.is_empty() {
-            Ok(())
-        } else {
-            //TODO block
-            Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many bytes buffered",
-            ))
+    /// Set the read timeout; `read` returns `TimedOut` if no data arrives within `dur`.
+    ///
+    /// `None` blocks indefinitely. A zero duration is rejected with `InvalidInput`.
+    pub fn set_read_timeout(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        check_timeout(dur)?;
+        self.read_timeout = dur;
+        Ok(())
+    }
+
+    /// Set the write timeout; `write` and `flush` return `TimedOut` if the send queue does not
+    /// drain within `dur`.
+    ///
+    /// `None` blocks indefinitely. A zero duration is rejected with `InvalidInput`.
+    pub fn set_write_timeout(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        check_timeout(dur)?;
+        self.write_timeout = dur;
+        Ok(())
+    }
+
+    /// Put the stream into or out of non-blocking mode.
+    ///
+    /// In non-blocking mode `read`, `write` and `flush` return `WouldBlock` instead of waiting;
+    /// use a [`Poller`](crate::poll::Poller) to learn when to retry.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.read_timeout)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.write_timeout)
+    }
+
+    /// Buffer up to `size` bytes for sending; a smaller buffer than what is already queued
+    /// only makes writes wait until it has drained below `size`.
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "send buffer size must not be zero",
+            ));
         }
+        let mut c = self.socket.c.lock().unwrap();
+        let before = c.availablity();
+        c.send_buffer = size;
+        let a = c.availablity();
+        // writers waiting for room may now have it
+        let wakers = c.take_wakers(a);
+        self.socket.wq.notify(a);
+        self.socket.wq.signal(a - before);
+        drop(c);
+        wakers.for_each(Waker::wake);
+        Ok(())
     }
-}
 
-impl TcpStream {
-    pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
-        let mut cm = self.cm.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or(io::Error::new(
-            io::ErrorKind::ConnectionAborted,
-            "stream was terminated unexpectedly",
-        ))?;
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        Ok(self.socket.c.lock().unwrap().send_buffer)
+    }
 
-        c.close();
+    /// Switch the stream to `algorithm`, which continues from the current congestion window.
+    pub fn set_congestion_algorithm(&self, algorithm: CongestionAlgorithm) -> io::Result<()> {
+        let mut c = self.socket.c.lock().unwrap();
+        c.set_congestion_algorithm(algorithm);
+        // the new window may let more out right away
+        self.cm.schedule(self.quad, &mut c);
         Ok(())
     }
+
+    /// The built-in algorithm of the stream; while a controller set with
+    /// [`set_congestion_control`](Self::set_congestion_control) is in charge, the one it took
+    /// over from.
+    pub fn congestion_algorithm(&self) -> io::Result<CongestionAlgorithm> {
+        Ok(self.socket.c.lock().unwrap().congestion_algorithm())
+    }
+
+    /// Whether a controller set with [`set_congestion_control`](Self::set_congestion_control)
+    /// is in charge, until [`set_congestion_algorithm`](Self::set_congestion_algorithm) hands
+    /// the stream back to a built-in algorithm.
+    pub fn has_custom_congestion_control(&self) -> io::Result<bool> {
+        Ok(self
+            .socket
+            .c
+            .lock()
+            .unwrap()
+            .has_custom_congestion_control())
+    }
+
+    /// Let `controller` decide how much the stream has in flight from now on, and how fast it
+    /// is sent.
+    pub fn set_congestion_control<C>(&self, controller: C) -> io::Result<()>
+    where
+        C: CongestionControl + 'static,
+    {
+        let mut c = self.socket.c.lock().unwrap();
+        c.set_congestion_control(Box::new(controller));
+        self.cm.schedule(self.quad, &mut c);
+        Ok(())
+    }
+}
+
+/// The descriptor is an eventfd that becomes readable whenever `packet_loop` marks the stream
+/// readable or writable. It is reset when a non-blocking `read`, `write` or `flush` returns
+/// `WouldBlock`, so it can be registered with epoll or mio alongside other descriptors.
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        // hold the lock until the fd is stored, so that packet_loop cannot miss it
+        let c = self.socket.c.lock().unwrap();
+        self.socket
+            .wq
+            .fd
+            .get_or_init(|| {
+                let fd = EventFd::new().expect("failed to create eventfd");
+                if !c.availablity().is_empty() {
+                    fd.signal();
+                }
+                fd
+            })
+            .as_raw_fd()
+    }
 }
 
 impl Drop for TcpStream {
     fn drop(&mut self) {
-        let mut cm = self.cm.manager.lock().unwrap();
-        if let Some(c) = cm.connections.remove(&self.quad) {
-            // TODO: send FIN on cm.connections[quad]
-            //    unimplemented!()
+        let mut c = self.socket.c.lock().unwrap();
+        if c.close().is_ok() {
+            // the FIN goes out from on_timers, and packet_loop removes the connection once
+            // the peer has acknowledged it
+            c.dropped = true;
+            self.cm.schedule(self.quad, &mut c);
+            return;
         }
+        drop(c);
+        self.cm.reap(self.quad, &self.socket);
     }
 }
 
 pub struct TcpListener {
     port: u16,
     cm: InterfaceHandle,
+    wq: Arc<WaitQueue>,
+    nonblocking: bool,
 }
 
 impl TcpListener {
     pub fn accept(&mut self) -> io::Result<TcpStream> {
-        let mut m = self.cm.manager.lock().unwrap();
-        loop {
-            if let Some(quad) = m
+        self.do_accept(None)
+    }
+
+    /// Like [`TcpListener::accept`], but returns `TimedOut` if no connection arrives within
+    /// `timeout`.
+    pub fn accept_timeout(&mut self, timeout: Duration) -> io::Result<TcpStream> {
+        check_timeout(Some(timeout))?;
+        self.do_accept(Some(Instant::now() + timeout))
+    }
+
+    /// Put the listener into or out of non-blocking mode, in which `accept` returns
+    /// `WouldBlock` when no connection is pending.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    /// Take the next queued connection; `None` means there is none yet.
+    fn try_accept(&self, cm: &mut ConnectionManager) -> io::Result<Option<TcpStream>> {
+        let (quad, socket) = loop {
+            let Some(quad) = cm
                 .pending
                 .get_mut(&self.port)
                 .expect("port closed while listener still active")
                 .pop_front()
-            {
-                return Ok(TcpStream {
-                    quad,
-                    cm: self.cm.clone(),
-                });
+            else {
+                if cm.terminate.is_some() {
+                    // nothing will be queued anymore
+                    return Err(shut_down());
+                }
+                return Ok(None);
+            };
+            // skip connections that went away before they were accepted
+            if let Some(socket) = cm.connections.get(&quad) {
+                break (quad, socket.clone());
+            }
+        };
+        Ok(Some(TcpStream {
+            quad,
+            cm: self.cm.clone(),
+            socket,
+            read_timeout: None,
+            write_timeout: None,
+            nonblocking: false,
+        }))
+    }
+
+    fn do_accept(&mut self, deadline: Option<Instant>) -> io::Result<TcpStream> {
+        let mut m = self.cm.manager.lock().unwrap();
+        loop {
+            if let Some(stream) = self.try_accept(&mut m)? {
+                return Ok(stream);
+            }
+            if self.nonblocking {
+                self.wq.drain_fd();
+                return Err(would_block());
             }
-            m = self.cm.pending_var.wait(m).unwrap();
+            m = wait_until(&self.wq.readers, m, deadline)?;
         }
     }
 }
 
+/// The descriptor is an eventfd that becomes readable when a new connection is queued. It is
+/// reset when a non-blocking `accept` returns `WouldBlock`.
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        // hold the lock until the fd is stored, so that packet_loop cannot miss it
+        let cm = self.cm.manager.lock().unwrap();
+        self.wq
+            .fd
+            .get_or_init(|| {
+                let fd = EventFd::new().expect("failed to create eventfd");
+                if cm.pending.get(&self.port).is_some_and(|p| !p.is_empty()) {
+                    fd.signal();
+                }
+                fd
+            })
+            .as_raw_fd()
+    }
+}
+
 impl Drop for TcpListener {
     fn drop(&mut self) {
         let mut cm = self.cm.manager.lock().unwrap();
+        cm.listeners.remove(&self.port);
+        cm.accept_wakers.remove(&self.port);
         let pending = cm
             .pending
             .remove(&self.port)
             .expect("port closed while listener still active");
 
         for quad in pending {
-            //TODO: terminate cm.connections[quad]
-            unimplemented!()
+            // TODO: send RST for cm.connections[quad]
+            cm.connections.remove(&quad);
+        }
+    }
+}
+
+pub struct UdpSocket {
+    port: u16,
+    cm: InterfaceHandle,
+    socket: UdpHandle,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nonblocking: bool,
+}
+
+impl UdpSocket {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.socket.b.lock().unwrap().local)
+    }
+
+    /// The address set by [`UdpSocket::connect`]; `NotConnected` if there is none.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.b.lock().unwrap().peer.ok_or(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "socket is not connected",
+        ))
+    }
+
+    /// Only exchange datagrams with `addr` from now on: `send` and `recv` go to and come from
+    /// it, datagrams from anyone else are dropped, and ICMP errors about datagrams sent to it
+    /// are returned by the next call.
+    pub fn connect(&self, addr: impl Into<SocketAddr>) -> io::Result<()> {
+        let peer = addr.into();
+        let mut b = self.socket.b.lock().unwrap();
+        self.source(&b, peer)?;
+        b.peer = Some(peer);
+        b.error = None;
+        Ok(())
+    }
+
+    /// Send `buf` as one datagram to `addr`.
+    pub fn send_to(&self, buf: &[u8], addr: impl Into<SocketAddr>) -> io::Result<usize> {
+        let dst = addr.into();
+        let src = {
+            let mut b = self.socket.b.lock().unwrap();
+            Self::check(&mut b)?;
+            self.source(&b, dst)?
+        };
+        self.do_send(src, dst, buf)
+    }
+
+    /// Send `buf` as one datagram to the connected peer.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let (src, dst) = {
+            let mut b = self.socket.b.lock().unwrap();
+            Self::check(&mut b)?;
+            let dst = b.peer.ok_or(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "socket is not connected",
+            ))?;
+            (self.source(&b, dst)?, dst)
+        };
+        self.do_send(src, dst, buf)
+    }
+
+    /// Receive one datagram into `buf`, returning its length and sender; whatever does not fit
+    /// into `buf` is discarded.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let deadline = self.read_timeout.map(|t| Instant::now() + t);
+        let mut b = self.socket.b.lock().unwrap();
+        loop {
+            Self::check(&mut b)?;
+            if let Some(received) = b.take(buf) {
+                return Ok(received);
+            }
+
+            if self.nonblocking {
+                self.socket.wq.drain_fd();
+                return Err(would_block());
+            }
+            b = wait_until(&self.socket.wq.readers, b, deadline)?;
+        }
+    }
+
+    /// Like [`UdpSocket::recv_from`], for sockets that only hear from their connected peer.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).map(|(n, _)| n)
+    }
+
+    /// Fail with the pending ICMP error, if any, which is then cleared, or once the interface
+    /// has shut down.
+    fn check(b: &mut udp::Binding) -> io::Result<()> {
+        if let Some(kind) = b.error.take() {
+            return Err(kind.into());
+        }
+        if b.shut_down {
+            return Err(shut_down());
+        }
+        Ok(())
+    }
+
+    /// The address to send datagrams to `dst` from.
+    fn source(&self, b: &udp::Binding, dst: SocketAddr) -> io::Result<SocketAddr> {
+        if b.local.is_ipv4() != dst.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "source and destination are of different address families",
+            ));
+        }
+        if !b.local.ip().is_unspecified() {
+            return Ok(b.local);
+        }
+        let ip = self
+            .cm
+            .addresses
+            .iter()
+            .find(|ip| ip.is_ipv4() == dst.is_ipv4())
+            .ok_or(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no local address of the family of the destination",
+            ))?;
+        Ok(SocketAddr::new(*ip, b.local.port()))
+    }
+
+    /// Queue a datagram for packet_loop, waiting for room if other datagrams fill the queue.
+    fn do_send(&self, src: SocketAddr, dst: SocketAddr, buf: &[u8]) -> io::Result<usize> {
+        udp::check_len(dst, buf.len(), self.cm.mtu)?;
+        let deadline = self.write_timeout.map(|t| Instant::now() + t);
+        let mut queue = self.cm.udp_out.lock().unwrap();
+        loop {
+            if self.cm.manager.lock().unwrap().terminate.is_some() {
+                return Err(shut_down());
+            }
+            // a datagram larger than the whole queue still goes out on its own
+            if queue.bytes + buf.len() <= udp::SEND_QUEUE_SIZE || queue.datagrams.is_empty() {
+                queue.bytes += buf.len();
+                queue.datagrams.push_back(udp::Outbound {
+                    src,
+                    dst,
+                    payload: buf.to_vec(),
+                });
+                drop(queue);
+                self.cm.wakeup.signal();
+                return Ok(buf.len());
+            }
+
+            if self.nonblocking {
+                return Err(would_block());
+            }
+            queue = wait_until(&self.cm.udp_out_space, queue, deadline)?;
+        }
+    }
+
+    /// Set the read timeout; `recv` and `recv_from` return `TimedOut` if no datagram arrives
+    /// within `dur`.
+    ///
+    /// `None` blocks indefinitely. A zero duration is rejected with `InvalidInput`.
+    pub fn set_read_timeout(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        check_timeout(dur)?;
+        self.read_timeout = dur;
+        Ok(())
+    }
+
+    /// Set the write timeout; `send` and `send_to` return `TimedOut` if the datagrams of the
+    /// interface do not leave room for theirs within `dur`.
+    ///
+    /// `None` blocks indefinitely. A zero duration is rejected with `InvalidInput`.
+    pub fn set_write_timeout(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        check_timeout(dur)?;
+        self.write_timeout = dur;
+        Ok(())
+    }
+
+    /// Put the socket into or out of non-blocking mode, in which receiving returns
+    /// `WouldBlock` instead of waiting for a datagram, and sending instead of waiting for room.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.read_timeout)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.write_timeout)
+    }
+}
+
+/// The descriptor is an eventfd that becomes readable when a datagram or an error arrives. It
+/// is reset when a non-blocking `recv` or `recv_from` returns `WouldBlock`.
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        // hold the lock until the fd is stored, so that packet_loop cannot miss it
+        let b = self.socket.b.lock().unwrap();
+        self.socket
+            .wq
+            .fd
+            .get_or_init(|| {
+                let fd = EventFd::new().expect("failed to create eventfd");
+                if b.availability().contains(Available::READ) {
+                    fd.signal();
+                }
+                fd
+            })
+            .as_raw_fd()
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let mut cm = self.cm.manager.lock().unwrap();
+        let Entry::Occupied(mut sockets) = cm.udp.entry(self.port) else {
+            unreachable!("sockets stay in the table until dropped");
+        };
+        sockets.get_mut().retain(|s| !Arc::ptr_eq(s, &self.socket));
+        if sockets.get().is_empty() {
+            sockets.remove();
         }
     }
 }
@@ -0,0 +1,201 @@
+//! The link layer: bare IP packets on a TUN device, or Ethernet II frames on a TAP device,
+//! where ARP finds the hardware addresses of IPv4 peers.
+
+use std::{
+    cell::RefCell,
+    io,
+    net::Ipv4Addr,
+    os::fd::{AsRawFd, RawFd},
+    time::Instant,
+};
+
+use etherparse::{ether_type, Ethernet2Header, Ethernet2HeaderSlice, SerializedSize};
+use tun_tap::Iface;
+
+use crate::arp::{self, Mac};
+
+/// The device the stack sends and receives on.
+///
+/// Only ever used from the packet loop.
+pub(crate) struct Nic {
+    iface: Iface,
+    ethernet: Option<Ethernet>,
+}
+
+struct Ethernet {
+    mac: Mac,
+    /// addresses ARP requests are answered for
+    local: Vec<Ipv4Addr>,
+    arp: RefCell<arp::Cache>,
+}
+
+impl Nic {
+    /// A TUN device, which carries bare IP packets.
+    pub(crate) fn tun(iface: Iface) -> Self {
+        Nic {
+            iface,
+            ethernet: None,
+        }
+    }
+
+    /// A TAP device, on which the stack has the hardware address `mac` and answers ARP for the
+    /// `local` addresses.
+    pub(crate) fn tap(iface: Iface, mac: Mac, local: Vec<Ipv4Addr>) -> Self {
+        Nic {
+            iface,
+            ethernet: Some(Ethernet {
+                mac,
+                local,
+                arp: RefCell::default(),
+            }),
+        }
+    }
+
+    /// Bytes of link header in front of each IP packet.
+    pub(crate) fn header_len(&self) -> usize {
+        match self.ethernet {
+            Some(_) => Ethernet2Header::SERIALIZED_SIZE,
+            None => 0,
+        }
+    }
+
+    /// Read one packet or frame into `buf`, which must have room for the MTU and the link
+    /// header.
+    pub(crate) fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.iface.recv(buf)
+    }
+
+    /// The IP packet in `frame`, as read by [`Nic::recv`], if it carries one for us.
+    ///
+    /// ARP packets are handled here.
+    pub(crate) fn ip_packet<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        let Some(ethernet) = &self.ethernet else {
+            return Some(frame);
+        };
+        let header = Ethernet2HeaderSlice::from_slice(frame).ok()?;
+        // a TAP device in a bridge also sees frames flooded to other hosts
+        let dst = header.destination();
+        if dst != ethernet.mac && dst != arp::BROADCAST {
+            return None;
+        }
+        let payload = &frame[header.slice().len()..];
+        match header.ether_type() {
+            ether_type::IPV4 => Some(payload),
+            ether_type::ARP => {
+                if let Err(e) = self.on_arp(ethernet, payload) {
+                    eprintln!("failed to answer arp: {e}");
+                }
+                None
+            }
+            // IPv6 would need neighbor discovery
+            _ => None,
+        }
+    }
+
+    /// RFC 826: learn from any ARP packet, and answer requests for our addresses.
+    fn on_arp(&self, ethernet: &Ethernet, packet: &[u8]) -> io::Result<()> {
+        let Some(packet) = arp::Packet::parse(packet) else {
+            return Ok(());
+        };
+        let for_us = ethernet.local.contains(&packet.tpa);
+        // RFC 5227 S2.1.1: probes for an address in use have no sender address to learn
+        if !packet.spa.is_unspecified() {
+            let held =
+                ethernet
+                    .arp
+                    .borrow_mut()
+                    .learn(packet.spa, packet.sha, for_us, Instant::now());
+            for held in held {
+                self.send_frame(ethernet, packet.sha, ether_type::IPV4, &held)?;
+            }
+        }
+        if for_us && packet.request {
+            let reply = packet.reply(ethernet.mac);
+            self.send_frame(ethernet, packet.sha, ether_type::ARP, &reply.to_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Send the IP packet `packet`.
+    ///
+    /// On a TAP device, packets to IPv4 addresses that are not resolved yet are held until
+    /// they are, and dropped if they never are.
+    pub(crate) fn send(&self, packet: &[u8]) -> io::Result<()> {
+        let Some(ethernet) = &self.ethernet else {
+            self.iface.send(packet)?;
+            return Ok(());
+        };
+        if packet.first().map(|b| b >> 4) != Some(4) || packet.len() < 20 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only IPv4 can be sent on a TAP device",
+            ));
+        }
+        let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        let now = Instant::now();
+        let mut cache = ethernet.arp.borrow_mut();
+        if let Some(mac) = cache.lookup(dst, now) {
+            drop(cache);
+            return self.send_frame(ethernet, mac, ether_type::IPV4, packet);
+        }
+        if cache.hold(dst, src, packet.to_vec(), now) {
+            drop(cache);
+            self.send_request(ethernet, src, dst)?;
+        }
+        Ok(())
+    }
+
+    /// Send ARP requests again, for addresses that have not answered yet.
+    pub(crate) fn on_tick(&self) -> io::Result<()> {
+        let Some(ethernet) = &self.ethernet else {
+            return Ok(());
+        };
+        let resend = ethernet.arp.borrow_mut().on_tick(Instant::now());
+        for (dst, src) in resend {
+            self.send_request(ethernet, src, dst)?;
+        }
+        Ok(())
+    }
+
+    /// When [`Nic::on_tick`] has to be called next, if at all.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        let ethernet = self.ethernet.as_ref()?;
+        ethernet.arp.borrow().next_deadline()
+    }
+
+    fn send_request(&self, ethernet: &Ethernet, src: Ipv4Addr, dst: Ipv4Addr) -> io::Result<()> {
+        let request = arp::Packet::request(ethernet.mac, src, dst);
+        self.send_frame(
+            ethernet,
+            arp::BROADCAST,
+            ether_type::ARP,
+            &request.to_bytes(),
+        )
+    }
+
+    fn send_frame(
+        &self,
+        ethernet: &Ethernet,
+        dst: Mac,
+        ether_type: u16,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let header = Ethernet2Header {
+            destination: dst,
+            source: ethernet.mac,
+            ether_type,
+        };
+        let mut frame = Vec::with_capacity(header.header_len() + payload.len());
+        header.write(&mut frame)?;
+        frame.extend_from_slice(payload);
+        self.iface.send(&frame)?;
+        Ok(())
+    }
+}
+
+impl AsRawFd for Nic {
+    fn as_raw_fd(&self) -> RawFd {
+        self.iface.as_raw_fd()
+    }
+}
@@ -1,19 +1,24 @@
 use std::{
     io::{self, Read, Write},
+    net::Ipv4Addr,
     thread,
 };
 
 use rust_tcp::Interface;
 
 fn main() -> io::Result<()> {
-    let mut i = Interface::new()?;
+    let mut i = Interface::builder()
+        .address(Ipv4Addr::new(192, 168, 108, 1), 24)
+        .local_address(Ipv4Addr::new(192, 168, 108, 2))
+        .up(true)
+        .build()?;
     let mut t1 = i.bind(9000)?;
 
     let h1 = thread::spawn(move || {
         let mut buf = [0u8; 512];
         while let Ok(mut stream) = t1.accept() {
             eprintln!("get connection on 9000");
-            stream.write(b"hello from rust-tcp\n").unwrap();
+            stream.write_all(b"hello from rust-tcp\n").unwrap();
             stream.shutdown(std::net::Shutdown::Write).unwrap();
             loop {
                 let n = stream.read(&mut buf).unwrap();
@@ -0,0 +1,95 @@
+//! The persist timer (RFC 1122 S4.2.2.17): while the peer's window is closed and nothing is in
+//! flight, probe it now and then in case the update that opens it got lost.
+
+use std::time::{Duration, Instant};
+
+/// RFC 1122 S4.2.2.17: the longest the persist timer waits between probes of a zero window,
+/// borrowing RFC 6298's upper bound on the retransmission timeout.
+const MAX: Duration = Duration::from_secs(60);
+
+/// When the next zero window probe is due, and how many were sent.
+#[derive(Debug, Default)]
+pub(crate) struct Persist {
+    probe_at: Option<Instant>,
+    probes: u32,
+}
+
+impl Persist {
+    /// The window is closed with nothing in flight at `now`: whether a probe is due.
+    ///
+    /// The first probe waits one retransmission timeout, and every one after it twice as long
+    /// as the one before.
+    pub(crate) fn on_closed(&mut self, now: Instant, rto: Duration) -> bool {
+        match self.probe_at {
+            None => {
+                self.probe_at = Some(now + rto.min(MAX));
+                false
+            }
+            Some(at) if at <= now => {
+                self.probes += 1;
+                let backoff = rto.saturating_mul(1 << self.probes.min(16));
+                self.probe_at = Some(now + backoff.min(MAX));
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    /// The peer opened its window again.
+    pub(crate) fn on_open(&mut self) {
+        *self = Persist::default();
+    }
+
+    /// When the next probe is due, once the timer is armed.
+    pub(crate) fn probe_at(&self) -> Option<Instant> {
+        self.probe_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTO: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn first_probe_after_rto() {
+        let mut persist = Persist::default();
+        let start = Instant::now();
+        assert!(!persist.on_closed(start, RTO));
+        assert_eq!(persist.probe_at(), Some(start + RTO));
+        assert!(!persist.on_closed(start + RTO / 2, RTO));
+        assert!(persist.on_closed(start + RTO, RTO));
+    }
+
+    #[test]
+    fn backoff() {
+        let mut persist = Persist::default();
+        let mut now = Instant::now();
+        persist.on_closed(now, RTO);
+        let mut waits = Vec::new();
+        for _ in 0..8 {
+            let at = persist.probe_at().unwrap();
+            waits.push(at - now);
+            now = at;
+            assert!(persist.on_closed(now, RTO));
+        }
+        let secs: Vec<u64> = waits.iter().map(Duration::as_secs).collect();
+        assert_eq!(secs, [1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    #[test]
+    fn reopen() {
+        let mut persist = Persist::default();
+        let start = Instant::now();
+        persist.on_closed(start, RTO);
+        assert!(persist.on_closed(start + RTO, RTO));
+        persist.on_open();
+        assert_eq!(persist.probe_at(), None);
+
+        // closing again starts over from one retransmission timeout
+        let later = start + 10 * RTO;
+        assert!(!persist.on_closed(later, RTO));
+        assert_eq!(persist.probe_at(), Some(later + RTO));
+    }
+}
@@ -0,0 +1,309 @@
+//! Path MTU discovery: from ICMP reports (RFC 1191, RFC 8201), and in the packetization layer
+//! (RFC 4821) for paths that filter ICMP, by probing with larger segments and learning from
+//! whether they are acknowledged.
+
+use std::time::{Duration, Instant};
+
+use crate::tcp::wrapping_lt;
+
+/// RFC 1191 S6.3, RFC 4821 S7.7: how long sizes above a lowered path MTU are left alone before
+/// they are probed again, in case the path has changed.
+const AGING: Duration = Duration::from_secs(10 * 60);
+
+/// RFC 4821 S7.6.3: probes of one size lost in a row before that size is taken to be too big.
+const MAX_PROBES: u32 = 3;
+
+/// RFC 4821 S7.7: retransmission timeouts in a row before the path is taken to drop packets of
+/// the current size, instead of just being congested.
+const BLACKHOLE_TIMEOUTS: u32 = 2;
+
+/// The search ends once the path MTU is known to within this many bytes.
+const SEARCH_PRECISION: usize = 16;
+
+/// A probe in flight, the segment from `start` to `end`; acknowledging `end` proves that
+/// packets of `size` bytes get through.
+#[derive(Debug)]
+struct Probe {
+    start: u32,
+    end: u32,
+    size: usize,
+}
+
+/// What one connection knows about the MTU of its path, in bytes of IP packets.
+///
+/// Sizes up to `current` are known to get through, sizes above `high` are known not to, and
+/// the ones in between are searched by probing.
+#[derive(Debug)]
+pub(crate) struct PathMtu {
+    /// of the interface, which no packet may exceed
+    mtu: usize,
+    /// RFC 4821 S7.2: believed to get through any path, so where the search starts
+    base: usize,
+    /// RFC 791, RFC 8200 S5: below what every link supports, so no report is believed
+    min: usize,
+    current: usize,
+    high: usize,
+    probe: Option<Probe>,
+    // probes of the next size lost in a row
+    lost: u32,
+    // retransmission timeouts without progress
+    timeouts: u32,
+    // when high was last lowered below mtu
+    lowered: Option<Instant>,
+}
+
+impl PathMtu {
+    pub(crate) fn new(mtu: usize, v6: bool) -> Self {
+        let (min, base) = if v6 { (1280, 1280) } else { (576, 1024) };
+        let base = base.min(mtu);
+        PathMtu {
+            mtu,
+            base,
+            min: min.min(mtu),
+            current: base,
+            high: mtu,
+            probe: None,
+            lost: 0,
+            timeouts: 0,
+            lowered: None,
+        }
+    }
+
+    /// The MTU of the interface.
+    pub(crate) fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// The largest packets to send.
+    pub(crate) fn current(&self) -> usize {
+        self.current
+    }
+
+    /// The size of the next probe, at most `max` bytes, if the search is not over.
+    ///
+    /// Only one probe is in flight at a time.
+    pub(crate) fn probe_size(&mut self, max: usize) -> Option<usize> {
+        if self.probe.is_some() {
+            return None;
+        }
+        if self.lowered.is_some_and(|at| at.elapsed() >= AGING) {
+            self.high = self.mtu;
+            self.lowered = None;
+            self.lost = 0;
+        }
+        let high = self.high.min(max);
+        if high < self.current + SEARCH_PRECISION {
+            return None;
+        }
+        // binary search, rounding up so that the last probe can find `high` itself
+        Some(self.current + (high - self.current).div_ceil(2))
+    }
+
+    /// A probe of `size` bytes was sent, from sequence number `start` to just before `end`.
+    pub(crate) fn probe_sent(&mut self, start: u32, end: u32, size: usize) {
+        self.probe = Some(Probe { start, end, size });
+    }
+
+    /// The peer acknowledged everything before `ackn`, which is new.
+    pub(crate) fn on_ack(&mut self, ackn: u32) {
+        self.timeouts = 0;
+        if let Some(probe) = &self.probe {
+            if !wrapping_lt(ackn, probe.end) {
+                self.current = probe.size;
+                self.probe = None;
+                self.lost = 0;
+            }
+        }
+    }
+
+    /// The retransmission timer expired for the segment at `una`.
+    ///
+    /// Returns whether that segment was a probe, whose loss says nothing about congestion
+    /// (RFC 4821 S7.5).
+    pub(crate) fn on_timeout(&mut self, una: u32) -> bool {
+        if self.probe_lost(una) {
+            return true;
+        }
+        // a probe behind the lost segment is sent again in smaller pieces, so whether it would
+        // have got through is never known
+        self.probe = None;
+        self.timeouts += 1;
+        if self.timeouts >= BLACKHOLE_TIMEOUTS && self.current > self.base {
+            // packets that used to get through no longer do, without any ICMP telling us why
+            self.high = self.current - 1;
+            self.current = self.base;
+            self.lowered = Some(Instant::now());
+            self.timeouts = 0;
+            self.lost = 0;
+        }
+        false
+    }
+
+    /// Duplicate ACKs had the segment at `una` sent again.
+    pub(crate) fn on_fast_retransmit(&mut self, una: u32) {
+        self.probe_lost(una);
+    }
+
+    /// Whether the segment at `una`, which is about to be sent again, is the probe; if so, it
+    /// counts as lost (RFC 4821 S7.6.2), so that the ACK for its retransmission proves nothing.
+    fn probe_lost(&mut self, una: u32) -> bool {
+        let Some(probe) = self.probe.take_if(|probe| probe.start == una) else {
+            return false;
+        };
+        self.lost += 1;
+        if self.lost >= MAX_PROBES {
+            self.high = probe.size - 1;
+            self.lowered = Some(Instant::now());
+            self.lost = 0;
+        }
+        true
+    }
+
+    /// An ICMP error reported that a packet of `len` bytes did not fit a hop of `mtu` bytes.
+    ///
+    /// Returns whether the report is believed, so that the packet has to be sent again.
+    pub(crate) fn on_too_big(&mut self, mtu: usize, len: usize) -> bool {
+        // RFC 5927 S7.2: a packet that fit cannot have been too big
+        if len <= mtu {
+            return false;
+        }
+        // nor may forged reports shrink packets to nothing
+        let mtu = mtu.max(self.min);
+        if mtu >= self.high {
+            // RFC 1191 S6.1: reports only ever lower the path MTU
+            return false;
+        }
+        self.high = mtu;
+        self.current = self.current.min(mtu);
+        if self.probe.as_ref().is_some_and(|probe| probe.size > mtu) {
+            self.probe = None;
+            self.lost = 0;
+        }
+        self.lowered = Some(Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Probe the path until the search is over, the way a connection sending `mss` byte
+    /// segments would; packets above `path` bytes are dropped.
+    fn search(pmtu: &mut PathMtu, path: usize) {
+        let mut seq = 0u32;
+        while let Some(size) = pmtu.probe_size(1500) {
+            let end = seq.wrapping_add(size as u32);
+            pmtu.probe_sent(seq, end, size);
+            if size <= path {
+                pmtu.on_ack(end);
+            } else {
+                pmtu.on_timeout(seq);
+            }
+            seq = end;
+        }
+    }
+
+    #[test]
+    fn rises_on_a_clean_path() {
+        let mut pmtu = PathMtu::new(1500, false);
+        assert_eq!(pmtu.current(), 1024);
+        search(&mut pmtu, 1500);
+        assert!(pmtu.current() + SEARCH_PRECISION > 1500);
+    }
+
+    #[test]
+    fn binary_search() {
+        let mut pmtu = PathMtu::new(1500, false);
+        assert_eq!(pmtu.probe_size(1500), Some(1262));
+        pmtu.probe_sent(0, 1222, 1262);
+        pmtu.on_ack(1222);
+        assert_eq!(pmtu.current(), 1262);
+        // halfway between what got through and the interface MTU
+        assert_eq!(pmtu.probe_size(1500), Some(1381));
+    }
+
+    #[test]
+    fn finds_a_smaller_path_mtu() {
+        let mut pmtu = PathMtu::new(1500, false);
+        search(&mut pmtu, 1400);
+        assert!(pmtu.current() <= 1400);
+        assert!(pmtu.current() + SEARCH_PRECISION > 1400);
+    }
+
+    #[test]
+    fn probes_fit_max() {
+        let mut pmtu = PathMtu::new(1500, false);
+        assert_eq!(pmtu.probe_size(1100), Some(1062));
+        assert_eq!(pmtu.probe_size(1030), None);
+    }
+
+    #[test]
+    fn one_probe_at_a_time() {
+        let mut pmtu = PathMtu::new(1500, false);
+        let size = pmtu.probe_size(1500).unwrap();
+        pmtu.probe_sent(0, 1000, size);
+        assert_eq!(pmtu.probe_size(1500), None);
+    }
+
+    #[test]
+    fn lost_probes_lower_high() {
+        let mut pmtu = PathMtu::new(1500, false);
+        for _ in 0..MAX_PROBES {
+            assert_eq!(pmtu.probe_size(1500), Some(1262));
+            pmtu.probe_sent(0, 1222, 1262);
+            assert!(pmtu.on_timeout(0));
+        }
+        assert_eq!(pmtu.current(), 1024);
+        assert_eq!(pmtu.probe_size(1500), Some(1143));
+    }
+
+    #[test]
+    fn retransmitted_probe_proves_nothing() {
+        let mut pmtu = PathMtu::new(1500, false);
+        pmtu.probe_sent(100, 1322, 1262);
+        // the probe is sent again in a smaller segment, which is then acked
+        pmtu.on_fast_retransmit(100);
+        pmtu.on_ack(1322);
+        assert_eq!(pmtu.current(), 1024);
+    }
+
+    #[test]
+    fn timeout_before_the_probe() {
+        let mut pmtu = PathMtu::new(1500, false);
+        pmtu.probe_sent(1000, 2222, 1262);
+        assert!(!pmtu.on_timeout(0));
+        pmtu.on_ack(2222);
+        assert_eq!(pmtu.current(), 1024);
+        // the probe was not what got lost
+        assert_eq!(pmtu.lost, 0);
+    }
+
+    #[test]
+    fn blackhole() {
+        let mut pmtu = PathMtu::new(1500, false);
+        search(&mut pmtu, 1500);
+        for _ in 0..BLACKHOLE_TIMEOUTS {
+            assert!(!pmtu.on_timeout(0));
+        }
+        assert_eq!(pmtu.current(), 1024);
+        // sizes that used to get through are searched again, up to just below them
+        let high = pmtu.high;
+        assert!(high < 1500);
+        assert_eq!(
+            pmtu.probe_size(1500),
+            Some(1024 + (high - 1024).div_ceil(2))
+        );
+    }
+
+    #[test]
+    fn too_big() {
+        let mut pmtu = PathMtu::new(1500, false);
+        search(&mut pmtu, 1500);
+        // a packet that fit, and a forged tiny MTU
+        assert!(!pmtu.on_too_big(1500, 1400));
+        assert!(pmtu.on_too_big(100, 1500));
+        assert_eq!(pmtu.current(), 576);
+        assert!(!pmtu.on_too_big(1400, 1500));
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    wait::{PollWaker, WaitQueue},
+    wait_until, ConnectionHandle, Interface, InterfaceHandle, TcpListener, TcpStream, UdpHandle,
+    UdpSocket,
+};
+
+pub use crate::tcp::Available;
+
+/// Identifies a registered source in the events returned by [`Poller::poll`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Token(pub usize);
+
+/// A readiness event for one registered source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    token: Token,
+    readiness: Available,
+}
+
+impl Event {
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    pub fn readiness(&self) -> Available {
+        self.readiness
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.readiness.contains(Available::READ)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.readiness.contains(Available::WRITE)
+    }
+}
+
+#[derive(Clone)]
+enum Source {
+    Stream(ConnectionHandle),
+    Listener(u16, Arc<WaitQueue>),
+    Udp(UdpHandle),
+}
+
+impl Source {
+    fn wq(&self) -> &WaitQueue {
+        match self {
+            Source::Stream(socket) => &socket.wq,
+            Source::Listener(_, wq) => wq,
+            Source::Udp(socket) => &socket.wq,
+        }
+    }
+}
+
+/// Something that can be registered with a [`Poller`].
+///
+/// Implemented for [`TcpStream`], [`TcpListener`] and [`UdpSocket`].
+pub trait Pollable: sealed::Sealed {}
+
+mod sealed {
+    use super::Source;
+    use crate::InterfaceHandle;
+
+    pub trait Sealed {
+        fn source(&self) -> SourceRef<'_>;
+    }
+
+    pub struct SourceRef<'a>(pub(super) &'a InterfaceHandle, pub(super) Source);
+}
+
+impl sealed::Sealed for TcpStream {
+    fn source(&self) -> sealed::SourceRef<'_> {
+        sealed::SourceRef(&self.cm, Source::Stream(self.socket.clone()))
+    }
+}
+impl Pollable for TcpStream {}
+
+impl sealed::Sealed for TcpListener {
+    fn source(&self) -> sealed::SourceRef<'_> {
+        sealed::SourceRef(&self.cm, Source::Listener(self.port, self.wq.clone()))
+    }
+}
+impl Pollable for TcpListener {}
+
+impl sealed::Sealed for UdpSocket {
+    fn source(&self) -> sealed::SourceRef<'_> {
+        sealed::SourceRef(&self.cm, Source::Udp(self.socket.clone()))
+    }
+}
+impl Pollable for UdpSocket {}
+
+/// Level-triggered readiness polling over many sockets of one [`Interface`].
+///
+/// Register sources with a [`Token`] and the readiness they are interested in, then call
+/// [`Poller::poll`] to wait for a batch of events. Sources should be put into non-blocking mode
+/// so that reads, writes and accepts past the reported readiness return `WouldBlock` instead of
+/// stalling the event loop.
+pub struct Poller {
+    cm: InterfaceHandle,
+    registrations: HashMap<Token, (Source, Available)>,
+    waker: Arc<PollWaker>,
+}
+
+impl Poller {
+    pub fn new(iface: &Interface) -> Self {
+        Poller {
+            cm: iface.cm.as_ref().unwrap().clone(),
+            registrations: HashMap::new(),
+            waker: Default::default(),
+        }
+    }
+
+    pub fn register<S: Pollable>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Available,
+    ) -> io::Result<()> {
+        let source = self.check_source(source)?;
+        if self.registrations.contains_key(&token) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "token already registered",
+            ));
+        }
+        source
+            .wq()
+            .add_poller(Arc::downgrade(&self.waker), token, interest);
+        self.registrations.insert(token, (source, interest));
+        // it may be ready already
+        self.waker.wake(token);
+        Ok(())
+    }
+
+    pub fn reregister<S: Pollable>(
+        &mut self,
+        source: &S,
+        token: Token,
+        interest: Available,
+    ) -> io::Result<()> {
+        let source = self.check_source(source)?;
+        match self.registrations.get_mut(&token) {
+            Some(r) => {
+                r.0.wq().remove_poller(&Arc::downgrade(&self.waker), token);
+                source
+                    .wq()
+                    .add_poller(Arc::downgrade(&self.waker), token, interest);
+                *r = (source, interest);
+                self.waker.wake(token);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "token is not registered",
+            )),
+        }
+    }
+
+    pub fn deregister(&mut self, token: Token) -> io::Result<()> {
+        let (source, _) = self.registrations.remove(&token).ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            "token is not registered",
+        ))?;
+        source
+            .wq()
+            .remove_poller(&Arc::downgrade(&self.waker), token);
+        self.waker.ready.lock().unwrap().remove(&token);
+        Ok(())
+    }
+
+    fn check_source<S: Pollable>(&self, source: &S) -> io::Result<Source> {
+        let sealed::SourceRef(cm, source) = source.source();
+        if !Arc::ptr_eq(cm, &self.cm) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "source belongs to a different interface",
+            ));
+        }
+        Ok(source)
+    }
+
+    /// Wait until at least one registered source is ready, or `timeout` passes.
+    ///
+    /// `events` is cleared and filled with one event per ready source; the number of events is
+    /// returned. A timeout of `None` blocks indefinitely, a zero timeout only checks readiness.
+    ///
+    /// Only the sources on the ready list are looked at: those that signalled new readiness
+    /// since the last poll, and those that were still ready then.
+    pub fn poll(
+        &mut self,
+        events: &mut Vec<Event>,
+        timeout: Option<Duration>,
+    ) -> io::Result<usize> {
+        events.clear();
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            let candidates: Vec<Token> = self.waker.ready.lock().unwrap().drain().collect();
+            for token in candidates {
+                let Some((source, interest)) = self.registrations.get(&token) else {
+                    // deregistered since it signalled
+                    continue;
+                };
+                let readiness = self.readiness(source) & *interest;
+                if !readiness.is_empty() {
+                    events.push(Event { token, readiness });
+                }
+            }
+            if !events.is_empty() {
+                // level-triggered: these stay on the list until a poll finds them not ready, and
+                // sources that become ready after they were checked signal again
+                let mut ready = self.waker.ready.lock().unwrap();
+                ready.extend(events.iter().map(Event::token));
+                return Ok(events.len());
+            }
+
+            let mut ready = self.waker.ready.lock().unwrap();
+            while ready.is_empty() {
+                ready = match wait_until(&self.waker.var, ready, deadline) {
+                    Ok(ready) => ready,
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => return Ok(0),
+                    Err(e) => return Err(e),
+                };
+            }
+        }
+    }
+
+    fn readiness(&self, source: &Source) -> Available {
+        match source {
+            Source::Stream(socket) => socket.c.lock().unwrap().availablity(),
+            Source::Listener(port, _) => {
+                let m = self.cm.manager.lock().unwrap();
+                match m.pending.get(port) {
+                    // accept fails once the interface shuts down
+                    Some(pending) if pending.is_empty() && m.terminate.is_none() => {
+                        Available::empty()
+                    }
+                    _ => Available::READ,
+                }
+            }
+            Source::Udp(socket) => socket.b.lock().unwrap().availability(),
+        }
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        let waker = Arc::downgrade(&self.waker);
+        for (&token, (source, _)) in &self.registrations {
+            source.wq().remove_poller(&waker, token);
+        }
+    }
+}
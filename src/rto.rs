@@ -0,0 +1,147 @@
+//! The retransmission timeout (RFC 6298): estimated from the round-trip times of segments that
+//! were sent only once (Karn's rule), and doubled on every timeout until a new estimate exists.
+
+use std::time::Duration;
+
+/// RFC 6298 S2.1: before any round-trip time was measured.
+const INITIAL: Duration = Duration::from_secs(1);
+
+/// RFC 6298 S5.7: after the SYN or SYN-ACK had to be sent again, until a round-trip time is
+/// measured.
+const INITIAL_AFTER_SYN_LOSS: Duration = Duration::from_secs(3);
+
+/// RFC 6298 S2.4: shorter timeouts would retransmit needlessly.
+const MIN: Duration = Duration::from_secs(1);
+
+/// RFC 6298 S2.5: the longest timeout, however often it was doubled.
+const MAX: Duration = Duration::from_secs(60);
+
+/// RFC 6298 S2: the granularity of our clock.
+const GRANULARITY: Duration = Duration::from_millis(1);
+
+/// The retransmission timeout of one connection.
+#[derive(Debug)]
+pub(crate) struct Rto {
+    /// the smoothed round-trip time and its variation, once one was measured
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    /// the timeout, including the backoff of timeouts since the last measurement
+    rto: Duration,
+}
+
+impl Default for Rto {
+    fn default() -> Self {
+        Rto {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL,
+        }
+    }
+}
+
+impl Rto {
+    pub(crate) fn get(&self) -> Duration {
+        self.rto
+    }
+
+    /// A segment that was only sent once was acknowledged `rtt` after it was sent
+    /// (RFC 6298 S2.2, S2.3).
+    pub(crate) fn on_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (3 * self.rttvar + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            }
+        }
+        let srtt = self.srtt.expect("just measured");
+        self.rto = (srtt + GRANULARITY.max(4 * self.rttvar)).clamp(MIN, MAX);
+    }
+
+    /// The retransmission timer expired (RFC 6298 S5.5).
+    pub(crate) fn on_timeout(&mut self) {
+        self.rto = self.rto.saturating_mul(2).min(MAX);
+    }
+
+    /// The handshake completed after our SYN or SYN-ACK had to be sent again (RFC 6298 S5.7).
+    pub(crate) fn on_syn_loss(&mut self) {
+        if self.srtt.is_none() {
+            self.rto = INITIAL_AFTER_SYN_LOSS;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn initial() {
+        assert_eq!(Rto::default().get(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn first_sample() {
+        let mut rto = Rto::default();
+        rto.on_sample(400 * MS);
+        // SRTT + 4 * RTT / 2
+        assert_eq!(rto.get(), 1200 * MS);
+    }
+
+    #[test]
+    fn smoothing() {
+        let mut rto = Rto::default();
+        rto.on_sample(400 * MS);
+        rto.on_sample(800 * MS);
+        // RTTVAR = 3/4 * 200 + 1/4 * 400, SRTT = 7/8 * 400 + 1/8 * 800
+        assert_eq!(rto.srtt, Some(450 * MS));
+        assert_eq!(rto.rttvar, 250 * MS);
+        assert_eq!(rto.get(), 1450 * MS);
+    }
+
+    #[test]
+    fn bounds() {
+        let mut rto = Rto::default();
+        rto.on_sample(10 * MS);
+        assert_eq!(rto.get(), MIN);
+        rto.on_sample(Duration::from_secs(100));
+        assert_eq!(rto.get(), MAX);
+    }
+
+    #[test]
+    fn backoff() {
+        let mut rto = Rto::default();
+        rto.on_timeout();
+        assert_eq!(rto.get(), Duration::from_secs(2));
+        rto.on_timeout();
+        assert_eq!(rto.get(), Duration::from_secs(4));
+        for _ in 0..10 {
+            rto.on_timeout();
+        }
+        assert_eq!(rto.get(), MAX);
+        // a new measurement collapses the backoff
+        rto.on_sample(100 * MS);
+        assert_eq!(rto.get(), MIN);
+    }
+
+    #[test]
+    fn syn_loss() {
+        let mut rto = Rto::default();
+        rto.on_timeout();
+        rto.on_timeout();
+        rto.on_syn_loss();
+        assert_eq!(rto.get(), Duration::from_secs(3));
+
+        // a measured round-trip time is better than any default
+        let mut rto = Rto::default();
+        rto.on_sample(2 * MIN);
+        let measured = rto.get();
+        rto.on_syn_loss();
+        assert_eq!(rto.get(), measured);
+    }
+}
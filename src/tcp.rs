@@ -1,33 +1,62 @@
 use std::collections::BTreeMap;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use std::task::Waker;
 use std::time::{Duration, Instant};
 use std::{collections::VecDeque, io};
 
 use bitflags::bitflags;
-use etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice, WriteError};
-use tun_tap::Iface;
+use etherparse::{IpNumber, TcpHeader, TcpHeaderSlice, TcpOptionElement};
+
+use crate::{
+    congestion::{self, Congestion, CongestionAlgorithm, CongestionControl},
+    ecn::Ecn,
+    icmp,
+    ip::{self, EcnCodepoint, IpHeader, IpHeaderSlice},
+    link::Nic,
+    persist::Persist,
+    pmtu::PathMtu,
+    rto::Rto,
+};
+
+/// How often the SYN or SYN-ACK is sent again before the handshake is given up; with the
+/// timeout doubling from one second, that takes about two minutes, as in Linux.
+const MAX_SYN_RETRIES: u32 = 6;
 
 bitflags! {
     #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-    pub(crate) struct Available: u8 {
+    /// Readiness of a stream or listener, also used as the interest set for
+    /// [`Poller`](crate::poll::Poller) registrations.
+    pub struct Available: u8 {
         const READ =  0b00000001;
         const WRITE = 0b00000010;
     }
 }
 
+/// Settings of new connections.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Options {
+    pub(crate) congestion: CongestionAlgorithm,
+    /// whether to negotiate ECN
+    pub(crate) ecn: bool,
+    pub(crate) send_buffer: usize,
+}
+
 #[derive(Debug)]
 pub enum State {
+    SynSent,
     SyncRcvd,
     Estab,
     FinWait1,
     FinWait2,
     TimeWait,
+    /// aborted, nothing is sent or accepted anymore
+    Closed,
 }
 
 impl State {
-    fn is_synchronized(&self) -> bool {
+    pub(crate) fn is_synchronized(&self) -> bool {
         match *self {
-            Self::SyncRcvd => false,
+            Self::SynSent | Self::SyncRcvd | Self::Closed => false,
             Self::Estab | Self::FinWait1 | Self::FinWait2 | Self::TimeWait => true,
         }
     }
@@ -37,7 +66,7 @@ impl State {
 pub struct Connection {
     send: SendSequenceSpace,
     recv: RecvSequenceSpace,
-    ip: Ipv4Header,
+    ip: IpHeader,
     tcp: TcpHeader,
     timer: Timers,
 
@@ -45,15 +74,38 @@ pub struct Connection {
     pub(crate) closed: bool,
     pub(crate) incoming: VecDeque<u8>,
     pub(crate) unacked: VecDeque<u8>,
+    // the most bytes unacked may hold
+    pub(crate) send_buffer: usize,
+    // tasks waiting in TcpStream::poll_read and poll_write/poll_flush
+    pub(crate) read_waker: Option<Waker>,
+    pub(crate) write_waker: Option<Waker>,
+    // the deadline packet_loop currently has queued for this connection
+    pub(crate) scheduled: Option<Instant>,
+    // the stream was dropped, so only the connection table still holds the connection
+    pub(crate) dropped: bool,
     // keep track of the sequence number we used for the fin if we have sent
     closed_at: Option<u32>,
+    // the largest segment the peer accepts (RFC 1122 S4.2.2.6)
+    send_mss: usize,
+    path: PathMtu,
+    congestion: Congestion,
+    ecn: Ecn,
+    /// why the connection was closed, unless it was aborted locally
+    pub(crate) error: Option<io::ErrorKind>,
+    /// the last soft error reported by ICMP since the connection last made progress
+    pub(crate) soft_error: Option<io::ErrorKind>,
 }
 
 #[derive(Debug)]
 struct Timers {
-    last_send: Instant,
     send_tiems: BTreeMap<u32, Instant>,
-    srtt: Duration,
+    rto: Rto,
+    /// Karn's rule: SND.MAX when something was last sent again, since the ACKs for segments
+    /// before it could be for either transmission and so measure no round-trip time
+    karn: Option<u32>,
+    /// how often the SYN or SYN-ACK was sent again
+    syn_retries: u32,
+    persist: Persist,
 }
 
 impl Connection {
@@ -84,33 +136,53 @@ impl Connection {
         Ok(())
     }
 
-    fn availablity(&self) -> Available {
+    /// Whether the stream was dropped and the connection has finished closing, so that it can
+    /// leave the connection table.
+    pub(crate) fn is_finished(&self) -> bool {
+        // TIME-WAIT is not held for 2 MSL: a retransmitted FIN of the peer goes unanswered
+        self.dropped && matches!(self.state, State::TimeWait | State::Closed)
+    }
+
+    pub(crate) fn availablity(&self) -> Available {
+        if let State::Closed = self.state {
+            // every operation fails now, so waiters must learn about it
+            return Available::all();
+        }
         let mut a = Available::empty();
         if self.is_rev_closed() || !self.incoming.is_empty() {
             a |= Available::READ;
         }
-        //TODO: take into account self.state
-        //TODO: set Available::WRITE
+        if self.state.is_synchronized() && (self.closed || self.unacked.len() < self.send_buffer) {
+            // either there is room in the send queue, or writers need to learn that they can't
+            // write anymore
+            a |= Available::WRITE;
+        }
         a
     }
 
-    fn have_sent_fin(&self) -> bool {
-        match self.state {
-            State::SyncRcvd | State::Estab => false,
-            State::FinWait1 | State::FinWait2 | State::TimeWait => true,
-        }
+    /// Take the wakers of tasks waiting for any of the readiness in `a`.
+    pub(crate) fn take_wakers(&mut self, a: Available) -> impl Iterator<Item = Waker> {
+        let read = a
+            .contains(Available::READ)
+            .then(|| self.read_waker.take())
+            .flatten();
+        let write = a
+            .contains(Available::WRITE)
+            .then(|| self.write_waker.take())
+            .flatten();
+        read.into_iter().chain(write)
     }
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub struct Quad {
-    pub src: (Ipv4Addr, u16),
-    pub dst: (Ipv4Addr, u16),
+    pub src: (IpAddr, u16),
+    pub dst: (IpAddr, u16),
 }
 
 /// State of Send Sequence Space (RFC 793 S3.2) F4
 ///
-/// ```
+/// ```text
 ///              1         2          3          4
 ///         ----------|----------|----------|----------
 ///                SND.UNA    SND.NXT    SND.UNA
@@ -127,10 +199,11 @@ struct SendSequenceSpace {
     una: u32,
     ///  send next
     nxt: u32,
+    /// the highest sequence number sent so far, which may still be acked after `nxt` went back
+    /// to send everything in flight again
+    max: u32,
     /// send window
     wnd: u32,
-    /// send urgent pointer
-    up: bool,
     /// segment sequence number used for last window update
     wl1: u32,
     /// segment acknowledgment number used for last window update
@@ -139,9 +212,9 @@ struct SendSequenceSpace {
     iss: u32,
 }
 
-///	State of Receive Sequence Space (RFC 793 S3.2) F5
+/// State of Receive Sequence Space (RFC 793 S3.2) F5
 ///
-/// ```
+/// ```text
 ///                 1          2          3
 ///             ----------|----------|----------
 ///                    RCV.NXT    RCV.NXT
@@ -157,68 +230,121 @@ struct RecvSequenceSpace {
     nxt: u32,
     /// receive window
     wnd: u32,
-    ///	receive urgent pointer
-    up: bool,
     /// initial receive sequence number
     irs: u32,
 }
 
 impl Connection {
-    pub(crate) fn on_tick(&mut self, nic: &Iface) -> io::Result<()> {
-        if let State::FinWait2 | State::TimeWait = self.state {
+    pub(crate) fn on_tick(&mut self, nic: &Nic) -> io::Result<()> {
+        if let State::SynSent | State::SyncRcvd = self.state {
+            // (re)transmit our SYN, or our SYN-ACK, until the peer answers it: nothing else
+            // could complete the handshake once it is lost
+            let rto = self.rto();
+            let sent_at = self.timer.send_tiems.get(&self.send.iss);
+            if sent_at.is_some_and(|t| t.elapsed() < rto) {
+                return Ok(());
+            }
+            if sent_at.is_some() {
+                if self.timer.syn_retries == MAX_SYN_RETRIES {
+                    self.state = State::Closed;
+                    self.error = Some(io::ErrorKind::TimedOut);
+                    self.timer.send_tiems.clear();
+                    return Ok(());
+                }
+                self.timer.syn_retries += 1;
+                self.timer.rto.on_timeout();
+                self.on_retransmit();
+            }
+            // the SYN occupies iss no matter how often it is sent
+            self.send.nxt = self.send.iss;
+            self.tcp.syn = true;
+            self.write(nic, self.send.iss, &[])?;
+            return Ok(());
+        }
+        if let State::FinWait2 | State::TimeWait | State::Closed = self.state {
             // we have shutdown our write side and the other side acked, no need to transmit anything
             return Ok(());
         }
 
         let nunacked = self.send.nxt.wrapping_sub(self.send.una);
-        let unsent = self.unacked.len() - nunacked as usize;
+        // a FIN in flight counts towards nunacked but has no byte in self.unacked
+        let unsent = self.unacked.len().saturating_sub(nunacked as usize);
 
-        let waited_for = self
-            .timer
-            .send_tiems
-            .range(self.send.una..)
-            .next()
-            .map(|(_, i)| i.elapsed());
-
-        let should_retransmit = if let Some(waited_for) = waited_for {
-            waited_for > Duration::from_secs(1)
-                && waited_for > Duration::from_nanos((15 * self.timer.srtt.as_nanos() / 10) as u64)
-        } else {
-            false
-        };
+        let should_retransmit = self.retransmit_at().is_some_and(|at| at <= Instant::now());
 
         if should_retransmit {
             // we should retransimt things!
-            let resend = self.unacked.len().min(self.send.wnd as usize);
-            if resend < self.send.wnd as usize && self.closed {
+            self.timer.rto.on_timeout();
+            self.on_retransmit();
+            if !self.path.on_timeout(self.send.una) {
+                self.congestion
+                    .on_timeout(self.send.nxt, nunacked, self.mss());
+            }
+            let window = self.window() as usize;
+            let resend = self.unacked.len().min(window).min(self.mss());
+            // the FIN follows the data, if it fits into the window as well
+            let everything = resend == self.unacked.len() && resend < window;
+            if everything && self.closed {
                 self.tcp.fin = true;
                 self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
             }
-            let payload = self.unacked.make_contiguous().to_vec();
-            self.write(nic, self.send.una, &payload[..resend])?;
-            self.send.nxt = self.send.una.wrapping_add(self.send.wnd);
+            let fin = self.tcp.fin;
+            // only what is sent, rather than the whole send buffer
+            let payload = self.unacked.make_contiguous()[..resend].to_vec();
+            self.write(nic, self.send.una, &payload)?;
+            // whatever followed the retransmitted segment is sent again as new data
+            self.send.nxt = self.send.una.wrapping_add(resend as u32 + fin as u32);
         } else {
             // we should send new data if we have new data and space in the window
             if unsent == 0 && self.closed_at.is_some() {
                 return Ok(());
             }
 
-            let allowed = self.send.wnd - nunacked;
+            if self.send.wnd == 0 && nunacked == 0 && (unsent > 0 || self.closed) {
+                return self.probe_window(nic);
+            }
+            let allowed = self.window().saturating_sub(nunacked);
             if allowed == 0 {
                 return Ok(());
             }
+            if self
+                .congestion
+                .send_at()
+                .is_some_and(|at| at > Instant::now())
+            {
+                // paced out, next_deadline has us back when it is time
+                return Ok(());
+            }
 
-            let send = unsent.min(allowed as usize);
-            if send < allowed as usize && self.closed && self.closed_at.is_none() {
+            let mut send = unsent.min(allowed as usize).min(self.mss());
+            let mut probe = None;
+            // RFC 4821 S7.6.1: probe with a larger segment, once that much data is queued; the
+            // probe never needs more than the send buffer holds
+            let headers = self.headers_len();
+            let max = self.send_mss.min(self.send_buffer) + headers;
+            if let Some(size) = self.path.probe_size(max) {
+                let len = size - headers;
+                if unsent >= len && allowed as usize >= len {
+                    send = len;
+                    probe = Some(size);
+                }
+            }
+            if send == unsent && send < allowed as usize && self.closed && self.closed_at.is_none()
+            {
                 self.tcp.fin = true;
                 self.closed_at = Some(self.send.nxt.wrapping_add(unsent as u32));
             }
-            let payload = self.unacked.make_contiguous().to_vec();
-            self.write(
-                nic,
-                self.send.nxt,
-                &payload[nunacked as usize..(nunacked as usize + send)],
-            )?;
+            if send == 0 && !self.tcp.fin {
+                // nothing new to send
+                return Ok(());
+            }
+            let payload = self.unacked.make_contiguous()[nunacked as usize..][..send].to_vec();
+            let start = self.send.nxt;
+            self.write(nic, start, &payload)?;
+            if let Some(size) = probe {
+                self.path
+                    .probe_sent(start, start.wrapping_add(send as u32), size);
+            }
         }
 
         // decide if it needs to send something
@@ -229,12 +355,187 @@ impl Connection {
         Ok(())
     }
 
+    /// How much may be in flight: what the peer accepts, and what the network is believed to.
+    fn window(&self) -> u32 {
+        self.send.wnd.min(self.congestion.window(self.mss()))
+    }
+
+    fn headers_len(&self) -> usize {
+        self.ip.header_len() + self.tcp.header_len() as usize
+    }
+
+    /// The largest payload of a segment: what the peer accepts, and fits the path MTU.
+    fn mss(&self) -> usize {
+        let fits = self.path.current().saturating_sub(self.headers_len());
+        self.send_mss.min(fits).max(1)
+    }
+
+    /// Handle an ICMP error about a segment of this connection (RFC 1122 S4.2.3.9).
+    ///
+    /// Returns `None` if the quoted segment is not in flight, so that the error cannot be about
+    /// it (RFC 5927 S4.1); blindly forged errors are likely to fail this check.
+    pub(crate) fn on_icmp(&mut self, report: &icmp::Report) -> Option<Available> {
+        // SND.UNA =< SEG.SEQ < SND.MAX
+        if !is_between_wrapped(self.send.una.wrapping_sub(1), report.seq, self.send.max) {
+            return None;
+        }
+        match report.problem {
+            icmp::Problem::TooBig { mtu } => {
+                if self.path.on_too_big(mtu, report.len) {
+                    self.resend_in_flight();
+                }
+            }
+            icmp::Problem::Unreachable { kind, hard: true } if !self.state.is_synchronized() => {
+                // the peer will not answer our SYN, or its SYN was not meant for us
+                self.state = State::Closed;
+                self.error = Some(kind);
+                self.unacked.clear();
+                self.timer.send_tiems.clear();
+            }
+            icmp::Problem::Unreachable { kind, .. } => {
+                // RFC 5927 S5.2: once synchronized, hard errors are treated as soft ones, so
+                // that they cannot be used to reset connections
+                self.soft_error = Some(kind);
+            }
+        }
+        Some(self.availablity())
+    }
+
+    /// Send everything in flight again right away, e.g. in smaller segments after one was
+    /// dropped for its size (RFC 1191 S6.5).
+    fn resend_in_flight(&mut self) {
+        if let State::Estab | State::FinWait1 = self.state {
+            self.on_retransmit();
+            self.send.nxt = self.send.una;
+            self.closed_at = None;
+            self.timer.send_tiems.clear();
+        }
+    }
+
+    pub(crate) fn congestion_algorithm(&self) -> CongestionAlgorithm {
+        self.congestion.algorithm()
+    }
+
+    pub(crate) fn has_custom_congestion_control(&self) -> bool {
+        self.congestion.is_custom()
+    }
+
+    pub(crate) fn set_congestion_algorithm(&mut self, algorithm: CongestionAlgorithm) {
+        self.congestion.set_algorithm(algorithm);
+    }
+
+    pub(crate) fn set_congestion_control(&mut self, controller: Box<dyn CongestionControl>) {
+        self.congestion.set_controller(controller);
+    }
+
+    /// Send the oldest unacknowledged segment again, without waiting for its retransmission
+    /// timeout (RFC 5681 S3.2, RFC 6582 S3.2).
+    fn retransmit_oldest(&mut self, nic: &Nic) -> io::Result<()> {
+        let len = self.unacked.len().min(self.mss());
+        if len == 0 {
+            // only the FIN is in flight, which its timeout takes care of
+            return Ok(());
+        }
+        let payload: Vec<u8> = self.unacked.range(..len).copied().collect();
+        self.on_retransmit();
+        self.write(nic, self.send.una, &payload)?;
+        Ok(())
+    }
+
+    /// The peer's window is closed and nothing is in flight, so no ACK is coming that could
+    /// open it; probe it from time to time in case its update got lost (RFC 1122 S4.2.2.17).
+    fn probe_window(&mut self, nic: &Nic) -> io::Result<()> {
+        let rto = self.rto();
+        if self.timer.persist.on_closed(Instant::now(), rto) {
+            // as in BSD and Linux, an ACK just below the window rather than a byte beyond it:
+            // the peer answers both with an ACK that carries its window, but this one takes up
+            // no sequence space that would have to be retransmitted
+            self.write(nic, self.send.una.wrapping_sub(1), &[])?;
+        }
+        Ok(())
+    }
+
+    /// The peer advertised `wnd` in a segment `seqn` that acknowledged `ackn` (RFC 793 S3.9).
+    fn update_window(&mut self, wnd: u32, seqn: u32, ackn: u32) {
+        self.send.wnd = wnd;
+        self.send.wl1 = seqn;
+        self.send.wl2 = ackn;
+        if wnd > 0 {
+            self.timer.persist.on_open();
+        }
+    }
+
+    fn rto(&self) -> Duration {
+        self.timer.rto.get()
+    }
+
+    /// The handshake completed.
+    fn on_established(&mut self) {
+        if self.timer.syn_retries > 0 {
+            self.timer.rto.on_syn_loss();
+        }
+    }
+
+    /// Something is about to be sent again, which no round-trip time may be measured from.
+    fn on_retransmit(&mut self) {
+        self.timer.karn = Some(self.send.max);
+    }
+
+    /// When the oldest unacknowledged segment is due for retransmission, if any is in flight.
+    fn retransmit_at(&self) -> Option<Instant> {
+        if self.send.nxt == self.send.una {
+            return None;
+        }
+        let rto = self.rto();
+        self.timer
+            .send_tiems
+            .range(self.send.una..)
+            .next()
+            .map(|(_, sent)| *sent + rto)
+    }
+
+    /// The next time `on_tick` has something to do for this connection, if ever.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::SynSent | State::SyncRcvd => {
+                return Some(
+                    self.timer
+                        .send_tiems
+                        .get(&self.send.iss)
+                        .map_or_else(Instant::now, |sent| *sent + self.rto()),
+                );
+            }
+            State::FinWait2 | State::TimeWait | State::Closed => return None,
+            State::Estab | State::FinWait1 => {}
+        }
+
+        let nunacked = self.send.nxt.wrapping_sub(self.send.una);
+        let unsent = self.unacked.len().saturating_sub(nunacked as usize);
+        let fin_pending = self.closed && self.closed_at.is_none();
+        if self.send.wnd == 0 && nunacked == 0 && (unsent > 0 || fin_pending) {
+            // the persist timer, which on_tick arms first
+            return Some(self.timer.persist.probe_at().unwrap_or_else(Instant::now));
+        }
+        let can_send = unsent > 0 && nunacked < self.window();
+        if can_send || fin_pending {
+            return Some(self.congestion.send_at().unwrap_or_else(Instant::now));
+        }
+        self.retransmit_at()
+    }
+
     pub(crate) fn on_packet<'a>(
         &mut self,
-        nic: &Iface,
+        nic: &Nic,
         tcph: TcpHeaderSlice<'a>,
         data: &'a [u8],
+        codepoint: EcnCodepoint,
     ) -> io::Result<Available> {
+        match self.state {
+            State::SynSent => return self.on_syn_sent(nic, tcph),
+            State::Closed => return Ok(self.availablity()),
+            _ => {}
+        }
+
         //
         // valid segment check
         // RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
@@ -252,32 +553,21 @@ impl Connection {
         let okay = if slen == 0 {
             // zero-length segment has separate rules for acceptance
             if self.recv.wnd == 0 {
-                if seqn != self.recv.nxt {
-                    false
-                } else {
-                    true
-                }
-            } else if !is_between_wrapped(nxt, seqn, wend) {
-                false
+                seqn == self.recv.nxt
             } else {
-                true
+                is_between_wrapped(nxt, seqn, wend)
             }
         } else {
-            if self.recv.wnd == 0 {
-                false
-            } else if !is_between_wrapped(nxt, seqn, wend)
-                && !is_between_wrapped(nxt, seqn + slen - 1, wend)
-            {
-                false
-            } else {
-                true
-            }
+            self.recv.wnd != 0
+                && (is_between_wrapped(nxt, seqn, wend)
+                    || is_between_wrapped(nxt, seqn.wrapping_add(slen - 1), wend))
         };
         if !okay {
             println!("NOT OKEY");
             self.write(nic, self.send.nxt, &[])?;
             return Ok(self.availablity());
         }
+        self.ecn.on_segment(&tcph, codepoint);
         //self.recv.nxt = seqn.wrapping_add(slen);
 
         //TODO: if _not_ acceptable, send ACK
@@ -305,6 +595,7 @@ impl Connection {
                 // must have ACKed our SYN, since we detected at lease one acked byte, and we have only
                 // sent one byte (the SYN)
                 self.state = State::Estab;
+                self.on_established();
             } else {
                 // TODO: reset <SEQ=SEG.ACK><CTL=RST>
             }
@@ -318,11 +609,17 @@ impl Connection {
         //    self.tcp.fin = true;
         //    self.write(nic, &[])?;
         //    self.state = State::FinWait1;
+        let mut action = congestion::Action::None;
+        let mut rtt = None;
         if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
+            let una = self.send.una;
+            let nunacked = self.send.nxt.wrapping_sub(self.send.una);
+            if is_between_wrapped(self.send.una, ackn, self.send.max.wrapping_add(1)) {
                 println!(
-                    "ack for {} (last: {}); prune in {:?}",
-                    ackn, self.send.una, self.unacked
+                    "ack for {} (last: {}); prune in {} bytes",
+                    ackn,
+                    self.send.una,
+                    self.unacked.len()
                 );
                 if !self.unacked.is_empty() {
                     let data_start = if self.send.una == self.send.iss {
@@ -336,30 +633,76 @@ impl Connection {
                         .len()
                         .min(ackn.wrapping_sub(data_start) as usize);
                     self.unacked.drain(..acked_data_end);
+                    let karn = self.timer.karn;
                     self.timer.send_tiems.retain(|seq, sent| {
-                        if is_between_wrapped(self.send.una, *seq, ackn) {
-                            let srtt = self.timer.srtt.as_nanos();
-                            self.timer.srtt = Duration::from_nanos(
-                                ((8 * srtt + 2 * sent.elapsed().as_nanos()) / 10) as u64,
-                            );
+                        // SND.UNA =< SEG.SEQ < SEG.ACK: the oldest segment has been acked too
+                        if is_between_wrapped(self.send.una.wrapping_sub(1), *seq, ackn) {
+                            // the segments are in order, so the last one sets the sample
+                            if karn.is_none_or(|karn| !wrapping_lt(*seq, karn)) {
+                                rtt = Some(sent.elapsed());
+                            }
                             false
                         } else {
                             true
                         }
                     });
+                    if let Some(rtt) = rtt {
+                        self.timer.rto.on_sample(rtt);
+                    }
+                    if karn.is_some_and(|karn| !wrapping_lt(ackn, karn)) {
+                        self.timer.karn = None;
+                    }
+                }
+                self.path.on_ack(ackn);
+                if wrapping_lt(self.send.nxt, ackn) {
+                    // what was sent before a retransmission timeout got there after all
+                    self.send.nxt = ackn;
+                }
+                // the SYN takes up sequence space, but not room in the network
+                let acked =
+                    ackn.wrapping_sub(self.send.una) - (self.send.una == self.send.iss) as u32;
+                if acked > 0 {
+                    let flight = self.send.nxt.wrapping_sub(ackn);
+                    action = self.congestion.on_ack(ackn, acked, flight, self.mss(), rtt);
                 }
                 self.send.una = ackn;
+                self.soft_error = None;
+            } else if ackn == self.send.una
+                && nunacked > 0
+                && !self.unacked.is_empty()
+                && data.is_empty()
+                && !tcph.syn()
+                && !tcph.fin()
+                && tcph.window_size() as u32 == self.send.wnd
+            {
+                // RFC 5681 S2: a duplicate ACK, sent for a segment that arrived out of order
+                action = self
+                    .congestion
+                    .on_dupack(ackn, self.send.nxt, nunacked, self.mss());
             }
-            // TODO: prune self.unacked
-            // TODO: if unacked empty and waiting flush, notify
-            // TODO: update window
-
-            // we don't support Write yet
-            if let State::Estab = self.state {
-                // TODO: needs to be stored in the retransmission queue !
-                self.tcp.fin = true;
-                self.state = State::FinWait1;
+            // RFC 793 S3.9: SND.UNA =< SEG.ACK =< SND.MAX, from a segment no older than the one
+            // that last updated the window
+            if is_between_wrapped(una.wrapping_sub(1), ackn, self.send.max.wrapping_add(1))
+                && (wrapping_lt(self.send.wl1, seqn)
+                    || (self.send.wl1 == seqn && !wrapping_lt(ackn, self.send.wl2)))
+            {
+                self.update_window(tcph.window_size() as u32, seqn, ackn);
             }
+            // RFC 3168 S6.1.2: the peer saw a CE mark, which is congestion without loss
+            if self.ecn.is_echo(&tcph)
+                && self.congestion.on_ecn(
+                    ackn,
+                    self.send.nxt,
+                    self.send.nxt.wrapping_sub(ackn),
+                    self.mss(),
+                )
+            {
+                self.ecn.on_reduced();
+            }
+        }
+        if action == congestion::Action::Retransmit {
+            self.path.on_fast_retransmit(self.send.una);
+            self.retransmit_oldest(nic)?;
         }
 
         if let State::FinWait1 = self.state {
@@ -391,7 +734,7 @@ impl Connection {
                 //  RCV.NXT over the data accepted, and adjusts RCV.WND as
                 //  apporopriate to the current buffer availability.  The total of
                 //  RCV.NXT and RCV.WND should not be reduced.
-                self.recv.nxt = seqn.wrapping_add(tcph.fin().into());
+                self.recv.nxt = seqn.wrapping_add(data.len() as u32);
 
                 //  Send an acknowledgment of the form:
                 //  <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
@@ -421,11 +764,14 @@ impl Connection {
         Ok(self.availablity())
     }
 
-    pub fn accept<'a>(
-        nic: &Iface,
-        iph: Ipv4HeaderSlice<'a>,
+    /// Answer a SYN received on a listening port, for an interface with an MTU of `mtu`.
+    pub(crate) fn accept<'a>(
+        nic: &Nic,
+        iph: &IpHeaderSlice<'a>,
         tcph: TcpHeaderSlice<'a>,
         data: &'a [u8],
+        mtu: usize,
+        options: Options,
     ) -> io::Result<Option<Self>> {
         if !tcph.syn() {
             // only expected SYN
@@ -434,40 +780,51 @@ impl Connection {
 
         let iss = 0;
         let wnd = 10;
+        let send_mss = announced_mss(&tcph, default_mss(iph.source_addr()));
         let mut c = Connection {
             state: State::SyncRcvd,
             send: SendSequenceSpace {
                 iss,
                 una: iss,
                 nxt: iss,
-                wnd,
-                up: false,
-                wl1: 0,
+                max: iss,
+                wnd: tcph.window_size() as u32,
+                wl1: tcph.sequence_number(),
                 wl2: 0,
             },
             recv: RecvSequenceSpace {
                 nxt: tcph.sequence_number() + 1,
                 wnd: tcph.window_size() as u32,
                 irs: tcph.sequence_number(),
-                up: false,
             },
-            ip: Ipv4Header::new(
-                0,
-                64,
+            send_mss,
+            ip: IpHeader::new(
+                iph.destination_addr(),
+                iph.source_addr(),
                 IpNumber::Tcp as u8,
-                iph.destination_addr().octets(),
-                iph.source_addr().octets(),
             ),
             tcp: TcpHeader::new(tcph.destination_port(), tcph.source_port(), iss, wnd as u16),
             incoming: Default::default(),
             unacked: Default::default(),
+            send_buffer: options.send_buffer,
+            read_waker: None,
+            write_waker: None,
+            scheduled: None,
+            dropped: false,
             closed: false,
             timer: Timers {
-                last_send: Instant::now(),
                 send_tiems: Default::default(),
-                srtt: Duration::from_secs(1 * 60),
+                rto: Rto::default(),
+                karn: None,
+                syn_retries: 0,
+                persist: Persist::default(),
             },
             closed_at: None,
+            path: PathMtu::new(mtu, iph.source_addr().is_ipv6()),
+            congestion: Congestion::new(iss, send_mss, options.congestion),
+            ecn: Ecn::accept(options.ecn, &tcph),
+            error: None,
+            soft_error: None,
         };
 
         c.tcp.syn = true;
@@ -485,89 +842,188 @@ impl Connection {
         Ok(Some(c))
     }
 
-    fn write(&mut self, nic: &Iface, seqn: u32, payload: &[u8]) -> io::Result<usize> {
-        use std::io::{Cursor, Write};
-        let mut cursor = Cursor::new([0u8; 1500]);
+    /// Create the TCB for an active open from `src` to `dst`.
+    ///
+    /// Nothing is sent yet; the SYN goes out on the next `on_tick`.
+    pub(crate) fn connect(
+        src: (IpAddr, u16),
+        dst: (IpAddr, u16),
+        mtu: usize,
+        options: Options,
+    ) -> Self {
+        let iss = 0;
+        let wnd = 10;
+        Connection {
+            state: State::SynSent,
+            send: SendSequenceSpace {
+                iss,
+                una: iss,
+                nxt: iss,
+                max: iss,
+                wnd,
+                wl1: 0,
+                wl2: 0,
+            },
+            recv: RecvSequenceSpace {
+                nxt: 0,
+                wnd: 0,
+                irs: 0,
+            },
+            send_mss: default_mss(dst.0),
+            ip: IpHeader::new(src.0, dst.0, IpNumber::Tcp as u8),
+            tcp: TcpHeader::new(src.1, dst.1, iss, wnd as u16),
+            incoming: Default::default(),
+            unacked: Default::default(),
+            send_buffer: options.send_buffer,
+            read_waker: None,
+            write_waker: None,
+            scheduled: None,
+            dropped: false,
+            closed: false,
+            timer: Timers {
+                send_tiems: Default::default(),
+                rto: Rto::default(),
+                karn: None,
+                syn_retries: 0,
+                persist: Persist::default(),
+            },
+            closed_at: None,
+            path: PathMtu::new(mtu, dst.0.is_ipv6()),
+            congestion: Congestion::new(iss, default_mss(dst.0), options.congestion),
+            ecn: Ecn::new(options.ecn),
+            error: None,
+            soft_error: None,
+        }
+    }
+
+    /// Handle a segment while we are waiting for the answer to our SYN (RFC 793 S3.9).
+    fn on_syn_sent(&mut self, nic: &Nic, tcph: TcpHeaderSlice) -> io::Result<Available> {
+        if tcph.ack() {
+            let ackn = tcph.acknowledgment_number();
+            // SND.UNA < SEG.ACK =< SND.NXT
+            if !is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
+                return Ok(self.availablity());
+            }
+        }
+        if tcph.rst() || !tcph.syn() || !tcph.ack() {
+            // TODO: simultaneous open and RST handling
+            return Ok(self.availablity());
+        }
+
+        self.recv.irs = tcph.sequence_number();
+        self.recv.nxt = tcph.sequence_number().wrapping_add(1);
+        self.recv.wnd = tcph.window_size() as u32;
+        self.update_window(
+            tcph.window_size() as u32,
+            tcph.sequence_number(),
+            tcph.acknowledgment_number(),
+        );
+        self.send_mss = announced_mss(&tcph, self.send_mss);
+        self.ecn.on_syn_ack(&tcph);
+        // the initial window depends on the size of the segments, which is only known now
+        self.congestion.set_mss(self.mss());
+        self.send.una = tcph.acknowledgment_number();
+        self.soft_error = None;
+        self.timer.send_tiems.clear();
+        self.state = State::Estab;
+        self.on_established();
+
+        // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
+        self.tcp.ack = true;
+        self.write(nic, self.send.nxt, &[])?;
+        Ok(self.availablity())
+    }
+
+    fn write(&mut self, nic: &Nic, seqn: u32, payload: &[u8]) -> io::Result<usize> {
         self.tcp.sequence_number = seqn;
         self.tcp.acknowledgment_number = self.recv.nxt;
 
-        let size = cursor
-            .get_ref()
-            .len()
-            .min(self.tcp.header_len() as usize + payload.len() + self.ip.header_len() as usize);
-
-        self.ip
-            .set_payload_len(size - self.ip.header_len() as usize)
-            .expect("invalid tcp payload len for too big");
+        // DF stays set, so the segment has to fit the interface unfragmented
+        let room = self.path.mtu() - self.ip.header_len() - self.tcp.header_len() as usize;
+        let payload = &payload[..payload.len().min(room)];
+        let payload_bytes = payload.len();
+        let new_data = payload_bytes > 0 && !wrapping_lt(seqn, self.send.max);
+        let codepoint = self.ecn.mark(&mut self.tcp, new_data);
+        self.ip.set_ecn(codepoint);
 
         self.tcp.checksum = self
-            .tcp
-            .calc_checksum_ipv4(&self.ip, payload)
+            .ip
+            .tcp_checksum(&self.tcp, payload)
             .expect("failed to compute checksum");
 
-        // if s/without_packet_info/new/:
-        // let eth_flag_proto = (eth_flags as u32) << 16 | eth_proto as u32;
-        // (&mut buf[..4]).copy_from_slice(eth_flag_proto.to_be_bytes().as_slice());
-
-        if let Err(e) = self.ip.write(&mut cursor) {
-            let error = match e {
-                WriteError::IoError(e) => e,
-                WriteError::SliceTooSmall(len) => io::Error::new(
-                    io::ErrorKind::Interrupted,
-                    format!("slice too small with length: {len}"),
-                ),
-                WriteError::ValueError(v) => io::Error::new(io::ErrorKind::Other, v.to_string()),
-            };
-            return Err(error);
-        };
-        self.tcp.write(&mut cursor)?;
-        let payload_bytes = cursor.write(payload)?;
-        let buf_length = cursor.position() as usize;
-        nic.send(&cursor.into_inner()[..buf_length])?;
+        let mut segment = Vec::with_capacity(self.tcp.header_len() as usize + payload_bytes);
+        self.tcp.write(&mut segment)?;
+        segment.extend_from_slice(payload);
+        ip::send(nic, &self.ip, &segment, self.path.mtu())?;
 
-        let next_seq = seqn.wrapping_add(payload_bytes as u32);
-        if self.tcp.syn {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
-            self.tcp.syn = false;
+        if payload_bytes > 0 {
+            let flight = self.send.nxt.wrapping_sub(self.send.una);
+            self.congestion.on_sent(
+                seqn.wrapping_add(payload_bytes as u32),
+                payload_bytes,
+                flight,
+            );
         }
-        if self.tcp.fin {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
-            self.tcp.fin = false;
+        if payload_bytes > 0 || self.tcp.syn || self.tcp.fin {
+            // only segments that occupy sequence space are ever retransmitted
+            self.timer.send_tiems.insert(seqn, Instant::now());
         }
+        // the SYN comes before the data and the FIN after it, and each takes up a number
+        let next_seq = seqn
+            .wrapping_add(self.tcp.syn as u32)
+            .wrapping_add(payload_bytes as u32)
+            .wrapping_add(self.tcp.fin as u32);
+        self.tcp.syn = false;
+        self.tcp.fin = false;
         if wrapping_lt(self.send.nxt, next_seq) {
             self.send.nxt = next_seq;
         }
-        self.timer.send_tiems.insert(seqn, Instant::now());
+        if wrapping_lt(self.send.max, self.send.nxt) {
+            self.send.max = self.send.nxt;
+        }
 
         Ok(payload_bytes)
     }
 
-    fn send_rst<'a>(&mut self, nic: &Iface) -> io::Result<()> {
-        self.tcp.rst = true;
-        // TODO: fix sequence numbers here
-        // If the incoming segment has an ACK field, the reset takes its
-        // sequence number from the ACK field of the segment, otherwise the
-        // reset has sequence number zero and the ACK field is set to the sum
-        // of the sequence number and segment length of the incoming segment.
-        // The connection remains in the same state.
-        //
-        // TODO: handle synchronized RST
-        //  If the connection is in a synchronized state (ESTABLISHED,
-        //  FIN-WAIT-1, FIN-WAIT-2, CLOSE-WAIT, CLOSING, LAST-ACK, TIME-WAIT),
-        //  any unacceptable segment (out of window sequence number or
-        //  unacceptible acknowledgment number) must elicit only an empty
-        //  acknowledgment segment containing the current send-sequence number
-        //  and an acknowledgment indicating the next sequence number expected
-        //  to be received, and the connection remains in the same state.
-        self.tcp.sequence_number = 0;
-        self.tcp.acknowledgment_number = 0;
+    /// Abort the connection (RFC 793 S3.9 ABORT call).
+    ///
+    /// The peer gets a reset if it may still expect anything from us, and everything queued in
+    /// either direction is dropped except data that has already been received.
+    pub(crate) fn abort(&mut self, nic: &Nic) -> io::Result<Available> {
+        let send_rst = matches!(self.state, State::SyncRcvd | State::Estab | State::FinWait1);
+        self.state = State::Closed;
+        self.unacked.clear();
+        self.timer.send_tiems.clear();
+        if send_rst {
+            // <SEQ=SND.NXT><CTL=RST>
+            self.tcp.rst = true;
+            let sent = self.write(nic, self.send.nxt, &[]);
+            self.tcp.rst = false;
+            sent?;
+        }
+        Ok(self.availablity())
+    }
+}
 
-        self.write(nic, self.send.nxt, &[])?;
-        Ok(())
+/// The MSS to assume for a peer that announces none (RFC 1122 S4.2.2.6, RFC 8200 S8.3).
+fn default_mss(peer: IpAddr) -> usize {
+    match peer {
+        IpAddr::V4(_) => 536,
+        IpAddr::V6(_) => 1220,
     }
 }
 
-fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
+/// The MSS announced in a SYN, or `default` without one.
+fn announced_mss(tcph: &TcpHeaderSlice, default: usize) -> usize {
+    tcph.options_iterator()
+        .find_map(|option| match option {
+            Ok(TcpOptionElement::MaximumSegmentSize(mss)) => Some(mss as usize),
+            _ => None,
+        })
+        .unwrap_or(default)
+}
+
+pub(crate) fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
     // From RFC1323:
     //     TCP determines if a data segment is "old" or "new" by testing
     //     whether its sequence number is within 2**31 bytes of the left edge
@@ -575,9 +1031,100 @@ fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
     //     insure that new data is never mistakenly considered old and vice-
     //     versa, the left edge of the sender's window has to be at most
     //     2**31 away from the right edge of the receiver's window.
-    lhs.wrapping_sub(rhs) > 2 ^ 31
+    lhs.wrapping_sub(rhs) > 1 << 31
 }
 
 fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
     wrapping_lt(start, x) && wrapping_lt(x, end)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const OPTIONS: Options = Options {
+        congestion: CongestionAlgorithm::NewReno,
+        ecn: false,
+        send_buffer: 64 * 1024,
+    };
+
+    const US: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+
+    /// A connection that has sent up to 2000, of which everything from 1000 is in flight.
+    fn established() -> Connection {
+        let mut c = Connection::connect((US, 40000), (PEER, 80), 1500, OPTIONS);
+        c.state = State::Estab;
+        c.send.una = 1000;
+        c.send.nxt = 2000;
+        c.send.max = 2000;
+        c
+    }
+
+    fn report(seq: u32, problem: icmp::Problem) -> icmp::Report {
+        icmp::Report {
+            protocol: 6,
+            quad: Quad {
+                src: (PEER, 80),
+                dst: (US, 40000),
+            },
+            seq,
+            len: 1500,
+            problem,
+        }
+    }
+
+    const UNREACHABLE: icmp::Problem = icmp::Problem::Unreachable {
+        kind: io::ErrorKind::HostUnreachable,
+        hard: false,
+    };
+
+    #[test]
+    fn icmp_in_window() {
+        // RFC 5927 S4.1: SND.UNA =< SEG.SEQ < SND.MAX
+        let mut c = established();
+        for seq in [999, 2000, 1000 + (1 << 31)] {
+            let report = report(seq, UNREACHABLE);
+            assert_eq!(c.on_icmp(&report), None, "{seq}");
+            assert_eq!(c.soft_error, None);
+        }
+        for seq in [1000, 1999] {
+            let report = report(seq, UNREACHABLE);
+            assert!(c.on_icmp(&report).is_some(), "{seq}");
+            assert_eq!(c.soft_error, Some(io::ErrorKind::HostUnreachable));
+        }
+    }
+
+    #[test]
+    fn icmp_hard_errors() {
+        let refused = icmp::Problem::Unreachable {
+            kind: io::ErrorKind::ConnectionRefused,
+            hard: true,
+        };
+        // RFC 5927 S5.2: only soft once synchronized
+        let mut c = established();
+        let report = report(1000, refused);
+        c.on_icmp(&report);
+        assert!(matches!(c.state, State::Estab));
+        assert_eq!(c.soft_error, Some(io::ErrorKind::ConnectionRefused));
+
+        // but the end of a connection that never got an answer
+        c.state = State::SynSent;
+        c.on_icmp(&report);
+        assert!(matches!(c.state, State::Closed));
+        assert_eq!(c.error, Some(io::ErrorKind::ConnectionRefused));
+    }
+
+    #[test]
+    fn icmp_too_big() {
+        let mut c = established();
+        let too_big = icmp::Problem::TooBig { mtu: 800 };
+        let report = report(1000, too_big);
+        c.on_icmp(&report);
+        assert_eq!(c.path.current(), 800);
+        // everything in flight goes out again, in smaller segments
+        assert_eq!(c.send.nxt, c.send.una);
+    }
+}
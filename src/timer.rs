@@ -0,0 +1,104 @@
+use std::{cmp::Reverse, collections::BinaryHeap, time::Instant};
+
+use crate::tcp;
+
+/// Deadlines of all connections, so that `packet_loop` can sleep until the nearest one instead
+/// of ticking every connection.
+///
+/// Entries are never removed early: a connection records the deadline it currently has queued
+/// in `Connection::scheduled`, and entries that no longer match it are skipped when they expire.
+#[derive(Default)]
+pub(crate) struct DeadlineQueue {
+    heap: BinaryHeap<Reverse<(Instant, tcp::Quad)>>,
+}
+
+impl DeadlineQueue {
+    pub(crate) fn push(&mut self, at: Instant, quad: tcp::Quad) {
+        self.heap.push(Reverse((at, quad)));
+    }
+
+    /// The nearest deadline, if any.
+    pub(crate) fn next(&self) -> Option<Instant> {
+        self.heap.peek().map(|Reverse((at, _))| *at)
+    }
+
+    /// Remove and return every entry that is due at `now`.
+    pub(crate) fn expired(&mut self, now: Instant) -> Vec<(Instant, tcp::Quad)> {
+        let mut expired = Vec::new();
+        while let Some(Reverse((at, _))) = self.heap.peek() {
+            if *at > now {
+                break;
+            }
+            let Reverse(entry) = self.heap.pop().unwrap();
+            expired.push(entry);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    use super::*;
+
+    fn quad(port: u16) -> tcp::Quad {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        tcp::Quad {
+            src: (ip, port),
+            dst: (ip, 80),
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let mut q = DeadlineQueue::default();
+        assert_eq!(q.next(), None);
+        assert!(q.expired(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn nearest_first() {
+        let now = Instant::now();
+        let mut q = DeadlineQueue::default();
+        q.push(now + Duration::from_secs(3), quad(3));
+        q.push(now + Duration::from_secs(1), quad(1));
+        q.push(now + Duration::from_secs(2), quad(2));
+        assert_eq!(q.next(), Some(now + Duration::from_secs(1)));
+
+        let expired = q.expired(now + Duration::from_secs(2));
+        let quads: Vec<_> = expired.iter().map(|&(_, quad)| quad).collect();
+        assert_eq!(quads, [quad(1), quad(2)]);
+        assert_eq!(q.next(), Some(now + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn not_yet_due() {
+        let now = Instant::now();
+        let mut q = DeadlineQueue::default();
+        q.push(now + Duration::from_secs(1), quad(1));
+        assert!(q.expired(now).is_empty());
+        assert_eq!(q.next(), Some(now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn stale_entries_stay_queued() {
+        // a connection that was rescheduled leaves its old entry behind, which expires as well
+        let now = Instant::now();
+        let mut q = DeadlineQueue::default();
+        q.push(now + Duration::from_secs(1), quad(1));
+        q.push(now + Duration::from_secs(2), quad(1));
+        let expired = q.expired(now + Duration::from_secs(2));
+        assert_eq!(
+            expired,
+            [
+                (now + Duration::from_secs(1), quad(1)),
+                (now + Duration::from_secs(2), quad(1))
+            ]
+        );
+        assert_eq!(q.next(), None);
+    }
+}
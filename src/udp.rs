@@ -0,0 +1,312 @@
+//! UDP (RFC 768): datagrams to and from the ports bound by [`UdpSocket`](crate::UdpSocket)s.
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::{ip, link::Nic, tcp::Available};
+
+pub(crate) const PROTO_UDP: u8 = 17;
+
+const HEADER_LEN: usize = 8;
+
+/// Bytes of received datagrams a socket holds before further ones are dropped.
+const RECV_QUEUE_SIZE: usize = 64 * 1024;
+
+/// Bytes of datagrams waiting for `packet_loop` to send them, across all sockets.
+pub(crate) const SEND_QUEUE_SIZE: usize = 64 * 1024;
+
+/// The largest payload, as far as the length fields of UDP and IPv4 go; IPv6 datagrams have to
+/// fit the MTU, since we do not fragment them.
+const MAX_PAYLOAD_V4: usize = u16::MAX as usize - 20 - HEADER_LEN;
+
+/// A datagram for `packet_loop` to send.
+#[derive(Debug)]
+pub(crate) struct Outbound {
+    pub(crate) src: SocketAddr,
+    pub(crate) dst: SocketAddr,
+    pub(crate) payload: Vec<u8>,
+}
+
+/// The datagrams waiting to be sent.
+#[derive(Debug, Default)]
+pub(crate) struct SendQueue {
+    pub(crate) datagrams: VecDeque<Outbound>,
+    pub(crate) bytes: usize,
+}
+
+/// What a bound port has received, and who it talks to.
+#[derive(Debug)]
+pub(crate) struct Binding {
+    /// the address datagrams have to be sent to; unspecified means any local address
+    pub(crate) local: SocketAddr,
+    /// set by `connect`: the only address datagrams are accepted from and sent to
+    pub(crate) peer: Option<SocketAddr>,
+    /// datagrams not read yet, with their senders
+    received: VecDeque<(SocketAddr, Vec<u8>)>,
+    bytes: usize,
+    /// from an ICMP error about a datagram sent to `peer`, returned by the next call
+    pub(crate) error: Option<io::ErrorKind>,
+    /// set once the interface shuts down
+    pub(crate) shut_down: bool,
+}
+
+impl Binding {
+    pub(crate) fn new(local: SocketAddr) -> Self {
+        Binding {
+            local,
+            peer: None,
+            received: VecDeque::new(),
+            bytes: 0,
+            error: None,
+            shut_down: false,
+        }
+    }
+
+    /// Whether a datagram sent to `dst` from `src` is for this socket.
+    pub(crate) fn accepts(&self, src: SocketAddr, dst: IpAddr) -> bool {
+        let local = if self.local.ip().is_unspecified() {
+            self.local.is_ipv4() == dst.is_ipv4()
+        } else {
+            self.local.ip() == dst
+        };
+        local && self.peer.is_none_or(|peer| peer == src)
+    }
+
+    /// Queue a datagram from `src`; returns whether there was room for it.
+    pub(crate) fn deliver(&mut self, src: SocketAddr, payload: &[u8]) -> bool {
+        if self.bytes + payload.len() > RECV_QUEUE_SIZE {
+            return false;
+        }
+        self.bytes += payload.len();
+        self.received.push_back((src, payload.to_vec()));
+        true
+    }
+
+    /// Take the next datagram, copying as much of it as fits into `buf`; the rest is lost.
+    pub(crate) fn take(&mut self, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        let (src, payload) = self.received.pop_front()?;
+        self.bytes -= payload.len();
+        let n = buf.len().min(payload.len());
+        buf[..n].copy_from_slice(&payload[..n]);
+        Some((n, src))
+    }
+
+    pub(crate) fn availability(&self) -> Available {
+        if self.shut_down {
+            // every operation fails now, so waiters must learn about it
+            return Available::all();
+        }
+        // sending only ever waits for packet_loop, which drains the send queue right away
+        let mut a = Available::WRITE;
+        if !self.received.is_empty() || self.error.is_some() {
+            a |= Available::READ;
+        }
+        a
+    }
+}
+
+/// Check that a datagram of `len` bytes can be sent to `dst` over an interface with `mtu`.
+pub(crate) fn check_len(dst: SocketAddr, len: usize, mtu: usize) -> io::Result<()> {
+    let max = match dst {
+        SocketAddr::V4(_) => MAX_PAYLOAD_V4,
+        SocketAddr::V6(_) => mtu.saturating_sub(40 + HEADER_LEN),
+    };
+    if len > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "datagram too large",
+        ));
+    }
+    Ok(())
+}
+
+/// Source port, destination port and payload of the UDP datagram in `packet`, if it is intact.
+pub(crate) fn parse<'a>(packet: &ip::Packet<'a>) -> Option<(u16, u16, &'a [u8])> {
+    let message = packet.payload;
+    if message.len() < HEADER_LEN {
+        return None;
+    }
+    let u16_at = |at: usize| u16::from_be_bytes([message[at], message[at + 1]]);
+    let len = u16_at(4) as usize;
+    if len < HEADER_LEN || len > message.len() {
+        return None;
+    }
+    let checksum = u16_at(6);
+    // RFC 768: a zero checksum was not computed, which RFC 8200 S8.1 no longer allows for IPv6
+    let v4 = matches!(packet.header, ip::IpHeaderSlice::V4(_));
+    if checksum == 0 && !v4 {
+        return None;
+    }
+    let message = &message[..len];
+    let packet = ip::Packet {
+        payload: message,
+        ..packet.clone()
+    };
+    if checksum != 0 && !ip::transport_checksum_ok(&packet) {
+        return None;
+    }
+    Some((u16_at(0), u16_at(2), &message[HEADER_LEN..]))
+}
+
+/// Send `datagram`, in fragments if it does not fit the MTU.
+pub(crate) fn send(nic: &Nic, datagram: &Outbound, mtu: usize) -> io::Result<()> {
+    let mut iph = ip::IpHeader::new(datagram.src.ip(), datagram.dst.ip(), PROTO_UDP);
+    // nothing here would make datagrams smaller for a smaller path MTU, so routers may split them
+    if let ip::IpHeader::V4(iph) = &mut iph {
+        iph.dont_fragment = false;
+    }
+    let message = self::datagram(datagram.src, datagram.dst, &datagram.payload);
+    ip::send(nic, &iph, &message, mtu)
+}
+
+/// The UDP header and payload of a datagram from `src` to `dst`.
+fn datagram(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let len = (HEADER_LEN + payload.len()) as u16;
+    let mut message = Vec::with_capacity(HEADER_LEN + payload.len());
+    message.extend_from_slice(&src.port().to_be_bytes());
+    message.extend_from_slice(&dst.port().to_be_bytes());
+    message.extend_from_slice(&len.to_be_bytes());
+    message.extend_from_slice(&[0, 0]);
+    message.extend_from_slice(payload);
+    let checksum = match ip::transport_checksum(src.ip(), dst.ip(), PROTO_UDP, &message) {
+        // RFC 768: a checksum that comes out as zero is sent as all ones
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    message[6..8].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use etherparse::{Ipv4Header, Ipv6Header};
+
+    use super::*;
+
+    const US: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+    const PEER: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const US6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+    const PEER6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+
+    /// An IP packet from `src` to `dst` that carries `message`.
+    fn packet(src: IpAddr, dst: IpAddr, message: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let len = message.len() as u16;
+                let iph = Ipv4Header::new(len, 64, PROTO_UDP, src.octets(), dst.octets());
+                iph.write(&mut packet).unwrap();
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                let iph = Ipv6Header {
+                    payload_length: message.len() as u16,
+                    next_header: PROTO_UDP,
+                    hop_limit: 64,
+                    source: src.octets(),
+                    destination: dst.octets(),
+                    ..Default::default()
+                };
+                iph.write(&mut packet).unwrap();
+            }
+            _ => unreachable!(),
+        }
+        packet.extend_from_slice(message);
+        packet
+    }
+
+    fn parsed(packet: &[u8]) -> Option<(u16, u16, Vec<u8>)> {
+        let packet = ip::parse(packet, &[]).unwrap();
+        parse(&packet).map(|(src, dst, payload)| (src, dst, payload.to_vec()))
+    }
+
+    #[test]
+    fn roundtrip() {
+        for (src, dst) in [(PEER.into(), US.into()), (PEER6.into(), US6.into())] {
+            let message = datagram(SocketAddr::new(src, 53), SocketAddr::new(dst, 5353), b"dns");
+            assert_eq!(message.len(), HEADER_LEN + 3);
+            assert_eq!(message[4..6], [0, 11]);
+            let got = parsed(&packet(src, dst, &message));
+            assert_eq!(got, Some((53, 5353, b"dns".to_vec())));
+        }
+    }
+
+    #[test]
+    fn zero_checksum() {
+        let src = SocketAddr::new(PEER.into(), 53);
+        let dst = SocketAddr::new(US.into(), 5353);
+        // a payload that makes the checksum come out as zero
+        let sum = datagram(src, dst, &[0, 0]);
+        let payload = [sum[6], sum[7]];
+        let message = datagram(src, dst, &payload);
+        assert_eq!(message[6..8], [0xff, 0xff]);
+        assert!(parsed(&packet(PEER.into(), US.into(), &message)).is_some());
+
+        // RFC 768: not computed, which is fine over IPv4 but not over IPv6
+        let mut message = datagram(src, dst, b"dns");
+        message[6..8].fill(0);
+        assert!(parsed(&packet(PEER.into(), US.into(), &message)).is_some());
+        assert!(parsed(&packet(PEER6.into(), US6.into(), &message)).is_none());
+    }
+
+    #[test]
+    fn malformed() {
+        let src = SocketAddr::new(PEER.into(), 53);
+        let dst = SocketAddr::new(US.into(), 5353);
+        let message = datagram(src, dst, b"dns");
+        let send = |message: &[u8]| parsed(&packet(PEER.into(), US.into(), message));
+
+        assert!(send(&message[..HEADER_LEN - 1]).is_none());
+        let mut corrupted = message.clone();
+        corrupted[HEADER_LEN] ^= 1;
+        assert!(send(&corrupted).is_none());
+        // the length field has to fit both the header and the packet
+        for len in [HEADER_LEN as u16 - 1, message.len() as u16 + 1] {
+            let mut bad = message.clone();
+            bad[4..6].copy_from_slice(&len.to_be_bytes());
+            assert!(send(&bad).is_none());
+        }
+        // and what goes beyond it is not part of the datagram
+        let mut padded = message.clone();
+        padded.extend_from_slice(&[0; 4]);
+        assert_eq!(send(&padded), Some((53, 5353, b"dns".to_vec())));
+    }
+
+    #[test]
+    fn binding() {
+        let peer = SocketAddr::new(PEER.into(), 53);
+        let mut b = Binding::new(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 5353));
+        assert!(b.accepts(peer, US.into()));
+        assert!(!b.accepts(SocketAddr::new(PEER6.into(), 53), US6.into()));
+        b.peer = Some(peer);
+        assert!(!b.accepts(SocketAddr::new(PEER.into(), 54), US.into()));
+
+        assert!(b.availability().contains(Available::WRITE));
+        assert!(!b.availability().contains(Available::READ));
+        assert!(b.deliver(peer, b"hello"));
+        assert!(b.availability().contains(Available::READ));
+        // what does not fit the buffer is lost
+        let mut buf = [0; 3];
+        assert_eq!(b.take(&mut buf), Some((3, peer)));
+        assert_eq!(&buf, b"hel");
+        assert_eq!(b.take(&mut buf), None);
+
+        let big = vec![0; RECV_QUEUE_SIZE];
+        assert!(b.deliver(peer, &big));
+        assert!(!b.deliver(peer, b"x"));
+    }
+
+    #[test]
+    fn max_len() {
+        let v4 = SocketAddr::new(PEER.into(), 53);
+        assert!(check_len(v4, MAX_PAYLOAD_V4, 1500).is_ok());
+        assert!(check_len(v4, MAX_PAYLOAD_V4 + 1, 1500).is_err());
+        let v6 = SocketAddr::new(PEER6.into(), 53);
+        assert!(check_len(v6, 1500 - 48, 1500).is_ok());
+        assert!(check_len(v6, 1500 - 47, 1500).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    collections::HashSet,
+    sync::{Condvar, Mutex, OnceLock, Weak},
+};
+
+use crate::{eventfd::EventFd, poll::Token, tcp::Available};
+
+/// Everyone that may be waiting on one stream or listener.
+///
+/// Each stream and listener has its own queue, so that `packet_loop` only wakes the threads,
+/// pollers and eventfd of the socket whose readiness actually changed.
+#[derive(Default)]
+pub(crate) struct WaitQueue {
+    /// threads blocked in `read`, or in `accept` for a listener
+    pub(crate) readers: Condvar,
+    /// threads blocked in `write`, `flush` or `connect`
+    pub(crate) writers: Condvar,
+    /// eventfd handed out through `AsRawFd`, created on first use
+    pub(crate) fd: OnceLock<EventFd>,
+    /// pollers that registered this socket, under which token, and the readiness they care about
+    pollers: Mutex<Vec<(Weak<PollWaker>, Token, Available)>>,
+}
+
+impl WaitQueue {
+    /// Wake blocked threads that wait for any of the readiness in `a`.
+    ///
+    /// Threads wait for more specific conditions than readiness (`flush` waits for the send
+    /// queue to drain), so they are woken whenever the readiness holds, not only when it appears.
+    /// Must be called with the lock guarding the socket held (the connection lock for streams,
+    /// the manager lock for listeners), so that waiters that checked their condition under the
+    /// same lock cannot miss the wakeup.
+    pub(crate) fn notify(&self, a: Available) {
+        if a.contains(Available::READ) {
+            self.readers.notify_all();
+        }
+        if a.contains(Available::WRITE) {
+            self.writers.notify_all();
+        }
+    }
+
+    /// Signal the eventfd and pollers that the readiness in `a` just appeared.
+    ///
+    /// Must be called with the lock guarding the socket held, like [`WaitQueue::notify`].
+    pub(crate) fn signal(&self, a: Available) {
+        if a.is_empty() {
+            return;
+        }
+        if let Some(fd) = self.fd.get() {
+            fd.signal();
+        }
+        self.pollers
+            .lock()
+            .unwrap()
+            .retain(|(poller, token, interest)| {
+                let Some(poller) = poller.upgrade() else {
+                    return false;
+                };
+                if interest.intersects(a) {
+                    poller.wake(*token);
+                }
+                true
+            });
+    }
+
+    /// Reset the eventfd once the socket has run out of readiness.
+    ///
+    /// Must be called with the lock guarding the socket held, like [`WaitQueue::notify`].
+    pub(crate) fn drain_fd(&self) {
+        if let Some(fd) = self.fd.get() {
+            fd.drain();
+        }
+    }
+
+    /// Register `poller`'s interest under `token`, replacing what it registered under that
+    /// token before; the same socket may be registered under other tokens as well.
+    pub(crate) fn add_poller(&self, poller: Weak<PollWaker>, token: Token, interest: Available) {
+        let mut pollers = self.pollers.lock().unwrap();
+        pollers.retain(|(p, t, _)| !(p.ptr_eq(&poller) && *t == token) && p.strong_count() > 0);
+        pollers.push((poller, token, interest));
+    }
+
+    pub(crate) fn remove_poller(&self, poller: &Weak<PollWaker>, token: Token) {
+        self.pollers
+            .lock()
+            .unwrap()
+            .retain(|(p, t, _)| !(p.ptr_eq(poller) && *t == token) && p.strong_count() > 0);
+    }
+}
+
+/// Ready list of one [`Poller`](crate::poll::Poller): the tokens whose sources may have become
+/// ready, which are all it looks at on its next poll.
+#[derive(Default)]
+pub(crate) struct PollWaker {
+    pub(crate) ready: Mutex<HashSet<Token>>,
+    pub(crate) var: Condvar,
+}
+
+impl PollWaker {
+    pub(crate) fn wake(&self, token: Token) {
+        self.ready.lock().unwrap().insert(token);
+        self.var.notify_one();
+    }
+}